@@ -95,6 +95,7 @@ impl<'a> DebugSession<'a> {
     }
 }
 
+#[derive(Clone)]
 pub struct Target<'a> {
     env: JNIEnv<'a>,
     instance: JObject<'a>,
@@ -129,6 +130,18 @@ impl<'a> Target<'a> {
         Ok(())
     }
 
+    pub fn is_connected(&self) -> Result<bool> {
+        const METHOD: &str = "isConnected";
+        const SIGNATURE: &str = "()Z";
+
+        let ret = self
+            .env
+            .call_method(self.instance, METHOD, SIGNATURE, &[])?
+            .z()?;
+
+        Ok(ret)
+    }
+
     pub fn reset(&self) -> Result<()> {
         const METHOD: &str = "reset";
         const SIGNATURE: &str = "()V";
@@ -195,6 +208,7 @@ pub enum Register {
     LR,
     PC,
     XPSR,
+    PRIMASK,
 }
 
 impl string::ToString for Register {
@@ -218,6 +232,7 @@ impl string::ToString for Register {
             Register::LR => "LR",
             Register::PC => "PC",
             Register::XPSR => "XPSR",
+            Register::PRIMASK => "PRIMASK",
         };
         res.to_owned()
     }
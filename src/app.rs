@@ -70,10 +70,33 @@ file will be saved and the path will be displayed. If OFF is specified then no l
             .value_delimiter(",")
             .require_delimiter(true)
             .validator(spi_pins_validate))
+        .arg(Arg::with_name("write-pacing")
+            .help("Delay in milliseconds between each block written to external flash")
+            .long_help(
+"Delay in milliseconds between each block written to external flash. Only needed for slow external \
+flash devices which drop writes even though the firmware waits for the previous program to complete.")
+            .long("write-pacing")
+            .value_name("MS")
+            .default_value("0")
+            .validator(is_zero_or_positive))
+        .arg(Arg::with_name("total-timeout")
+            .help("Abort a read or write which takes longer than the given number of seconds")
+            .long("total-timeout")
+            .value_name("SECS")
+            .validator(is_zero_or_positive))
+        .arg(Arg::with_name("temp-dir")
+            .help("Directory to write the firmware binary to, defaults to the system temp directory")
+            .long("temp-dir")
+            .value_name("DIR"))
+        .arg(Arg::with_name("stats")
+            .help("Print transfer statistics to stderr when done")
+            .long("stats"))
         .subcommand(subcommand_info())
         .subcommand(subcommand_erase())
         .subcommand(subcommand_read())
         .subcommand(subcommand_write())
+        .subcommand(subcommand_fill())
+        .subcommand(subcommand_discover())
 }
 
 fn subcommand_info() -> App<'static, 'static> {
@@ -106,6 +129,19 @@ fn subcommand_erase() -> App<'static, 'static> {
                 .long("mass-erase")
                 .conflicts_with_all(&["offset", "length"]),
         )
+        .arg(
+            Arg::with_name("preserve")
+                .help("Address range to keep intact during mass erase, can be given multiple times")
+                .long_help(
+"Address range to keep intact during mass erase, can be given multiple times. The data in the \
+range is read before the mass erase and written back after.")
+                .long("preserve")
+                .value_names(&["OFFSET", "LENGTH"])
+                .number_of_values(2)
+                .multiple(true)
+                .validator(is_zero_or_positive)
+                .requires("mass-erase"),
+        )
 }
 
 fn subcommand_read() -> App<'static, 'static> {
@@ -135,6 +171,11 @@ fn subcommand_read() -> App<'static, 'static> {
                 .value_name("FILE")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("hexdump")
+                .help("Output the read data as a hexdump, addressed by external flash offset")
+                .long("hexdump"),
+        )
 }
 
 fn subcommand_write() -> App<'static, 'static> {
@@ -159,6 +200,17 @@ back the same address range may yield different data than initially written.")
                 .short("p")
                 .long("in-place")
         )
+        .arg(
+            Arg::with_name("mass-erase")
+                .help("Mass erase the entire external flash before writing")
+                .long_help(
+"Mass erase the entire external flash before writing, instead of only erasing the touched \
+sectors. This is the fastest way to write a full flash image, but all data outside the write \
+address range is lost.")
+                .short("m")
+                .long("mass-erase")
+                .conflicts_with("in-place")
+        )
         .arg(
             Arg::with_name("offset")
                 .help("Offset of bytes into external flash device to start write")
@@ -184,6 +236,67 @@ back the same address range may yield different data than initially written.")
         )
 }
 
+fn subcommand_fill() -> App<'static, 'static> {
+    SubCommand::with_name("fill")
+        .about("Fill an address range on the external flash with a repeating 32-bit word")
+        .long_about(
+"Fill an address range on the external flash with a repeating 32-bit word. The range is written \
+in-place, so it should be erased beforehand.")
+        .arg(
+            Arg::with_name("offset")
+                .help("Offset of bytes into external flash device to start fill")
+                .value_name("OFFSET")
+                .index(1)
+                .validator(is_zero_or_positive)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("length")
+                .help("Length of bytes to fill from offset, must be a multiple of 4")
+                .value_name("LENGTH")
+                .index(2)
+                .validator(is_zero_or_positive)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("word")
+                .help("The 32-bit word to fill with, decimal or hex prefixed with 0x")
+                .value_name("WORD")
+                .index(3)
+                .validator(is_word)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("big-endian")
+                .help("Store the word in big-endian byte order instead of little-endian")
+                .long("big-endian"),
+        )
+}
+
+fn subcommand_discover() -> App<'static, 'static> {
+    SubCommand::with_name("discover")
+        .about("Scan for the DIO the external flash CSN is connected to")
+        .long_about(
+"Scan for the DIO the external flash CSN is connected to. MISO, MOSI and CLK are taken from \
+--spi-pins if given, otherwise the LaunchPad defaults are used. The device is reset for each DIO \
+tried.")
+        .arg(
+            Arg::with_name("csn-range")
+                .help("DIOs to try as CSN, from START up to and including END")
+                .long("csn-range")
+                .value_names(&["START", "END"])
+                .default_values(&["0", "31"])
+                .validator(spi_pins_validate),
+        )
+}
+
+pub fn parse_word(val: &str) -> Option<u32> {
+    match val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => val.parse::<u32>().ok(),
+    }
+}
+
 fn spi_pins_validate(dio: String) -> Result<(), String> {
     type ParsedSpiPin = u8;
 
@@ -204,3 +317,13 @@ fn is_zero_or_positive(val: String) -> Result<(), String> {
 
     Ok(())
 }
+
+fn is_word(val: String) -> Result<(), String> {
+    if parse_word(&val).is_none() {
+        return Err(String::from(
+            "Value must be a 32-bit unsigned integer, either decimal or hex prefixed with 0x",
+        ));
+    }
+
+    Ok(())
+}
@@ -9,12 +9,14 @@ use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str;
+use std::time::Duration;
 
 use snafu::{Backtrace, OptionExt, ResultExt, Snafu};
 
 use crate::app;
 use crate::command::{Command, Subcommand};
-use crate::types::{Device, SpiPins};
+use crate::firmware::FirmwareConfig;
+use crate::types::{Device, Endianness, SpiPins};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -94,6 +96,23 @@ impl ArgMatches {
     }
 }
 
+fn address_ranges(matches: &ArgMatches, name: &str) -> Result<Vec<(u32, u32)>> {
+    let values = matches.values_of_lossy(name).unwrap_or_default();
+    values
+        .chunks(2)
+        .map(|range| {
+            let parsed = match range {
+                [offset, length] => offset.parse().ok().zip(length.parse().ok()),
+                _ => None,
+            };
+            parsed.context(ParseArgument {
+                arg: name,
+                reason: "Expected pairs of offset and length",
+            })
+        })
+        .collect()
+}
+
 pub struct Args {
     matches: ArgMatches,
 }
@@ -145,12 +164,39 @@ impl Args {
         Ok(arg)
     }
 
+    fn firmware_config(&self) -> Result<FirmwareConfig> {
+        const ARG_WRITE_PACING: &str = "write-pacing";
+        let write_pacing = self
+            .matches
+            .parse_of_lossy(ARG_WRITE_PACING)?
+            .map(Duration::from_millis)
+            .context(MissingArgument {
+                arg: ARG_WRITE_PACING,
+            })?;
+
+        let total_timeout = self
+            .matches
+            .parse_of_lossy("total-timeout")?
+            .map(Duration::from_secs);
+
+        let temp_dir = self.matches.value_of_lossy("temp-dir").map(PathBuf::from);
+
+        Ok(FirmwareConfig {
+            write_pacing,
+            total_timeout,
+            temp_dir,
+            ..FirmwareConfig::default()
+        })
+    }
+
     fn subcommand(&self) -> Result<Subcommand> {
         Ok(match self.matches.subcommand() {
             ("info", _) => Subcommand::Info,
             ("erase", Some(matches)) => {
                 if matches.is_present("mass-erase") {
-                    Subcommand::MassErase
+                    Subcommand::MassErase {
+                        preserve: address_ranges(&matches, "preserve")?,
+                    }
                 } else {
                     Subcommand::SectorErase {
                         offset: matches
@@ -169,6 +215,7 @@ impl Args {
                 length: matches
                     .parse_of_lossy("length")?
                     .context(MissingArgument { arg: "length" })?,
+                hexdump: matches.is_present("hexdump"),
                 output: RefCell::new(
                     if let Some(output_path) = matches.value_of_lossy("output") {
                         Box::new(File::create(output_path).context(CreateStreamError {})?)
@@ -180,6 +227,7 @@ impl Args {
             ("write", Some(matches)) => Subcommand::Write {
                 verify: matches.is_present("verify"),
                 in_place: matches.is_present("in-place"),
+                mass_erase: matches.is_present("mass-erase"),
                 offset: matches
                     .parse_of_lossy("offset")?
                     .expect("Missing required argument 'offset'"),
@@ -190,6 +238,40 @@ impl Args {
                     Box::new(io::stdin())
                 }),
             },
+            ("fill", Some(matches)) => Subcommand::Fill {
+                offset: matches
+                    .parse_of_lossy("offset")?
+                    .context(MissingArgument { arg: "offset" })?,
+                length: matches
+                    .parse_of_lossy("length")?
+                    .context(MissingArgument { arg: "length" })?,
+                word: matches
+                    .value_of_lossy("word")
+                    .as_deref()
+                    .and_then(app::parse_word)
+                    .context(MissingArgument { arg: "word" })?,
+                endianness: if matches.is_present("big-endian") {
+                    Endianness::Big
+                } else {
+                    Endianness::Little
+                },
+            },
+            ("discover", Some(matches)) => {
+                let bounds: Vec<u8> = matches
+                    .values_of_lossy("csn-range")
+                    .context(MissingArgument { arg: "csn-range" })?
+                    .iter()
+                    .map(|bound| bound.parse())
+                    .collect::<Result<_, _>>()
+                    .ok()
+                    .context(ParseArgument {
+                        arg: "csn-range",
+                        reason: "DIOs must be unsigned 8-bit integers",
+                    })?;
+                Subcommand::Discover {
+                    csn_range: bounds[0]..=bounds[1],
+                }
+            }
             (subcmd, _) => InvalidSubcommand { subcmd }.fail()?,
         })
     }
@@ -201,6 +283,8 @@ impl Args {
             xds_id: self.xds_id()?,
             device: self.device()?,
             spi_pins: self.spi_pins()?,
+            firmware_config: self.firmware_config()?,
+            stats: self.matches.is_present("stats"),
             subcommand: self.subcommand()?,
         })
     }
@@ -9,6 +9,8 @@ use rust_embed::RustEmbed;
 
 use crate::types::{Device, DeviceFamily};
 
+// With the compress-assets feature rust-embed stores the files deflated and
+// inflates them in `Asset::get`, so callers always see the original bytes
 #[derive(RustEmbed)]
 #[folder = "./src/assets"]
 struct Asset;
@@ -42,3 +44,12 @@ pub fn get_firmware(device: Device) -> Option<Cow<'static, [u8]>> {
     };
     Asset::get(format!("{}{}", PATH, file).as_str())
 }
+
+/// The devices this build has both a ccxml template and a firmware binary for
+pub fn supported_devices() -> Vec<Device> {
+    Device::all()
+        .iter()
+        .copied()
+        .filter(|&device| get_ccxml_template(device).is_some() && get_firmware(device).is_some())
+        .collect()
+}
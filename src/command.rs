@@ -5,9 +5,11 @@
 
 use std::cell::RefCell;
 use std::io::{Read, Write};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
-use crate::types::{Device, SpiPins};
+use crate::firmware::FirmwareConfig;
+use crate::types::{Device, Endianness, SpiPins};
 
 pub enum Subcommand {
     Info,
@@ -15,19 +17,32 @@ pub enum Subcommand {
         offset: u32,
         length: u32,
     },
-    MassErase,
+    MassErase {
+        preserve: Vec<(u32, u32)>,
+    },
     Read {
         offset: u32,
         length: u32,
+        hexdump: bool,
         output: RefCell<Box<dyn Write>>,
     },
     Write {
         verify: bool,
         in_place: bool,
+        mass_erase: bool,
         offset: u32,
         length: Option<u32>,
         input: RefCell<Box<dyn Read>>,
     },
+    Fill {
+        offset: u32,
+        length: u32,
+        word: u32,
+        endianness: Endianness,
+    },
+    Discover {
+        csn_range: RangeInclusive<u8>,
+    },
 }
 
 pub struct Command {
@@ -36,5 +51,7 @@ pub struct Command {
     pub xds_id: String,
     pub device: Device,
     pub spi_pins: Option<SpiPins>,
+    pub firmware_config: FirmwareConfig,
+    pub stats: bool,
     pub subcommand: Subcommand,
 }
@@ -0,0 +1,85 @@
+// Copyright (c) 2020 , Texas Instruments.
+// Licensed under the BSD-3-Clause license
+// (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
+// notice may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiffSummary {
+    pub bytes: usize,
+    pub runs: usize,
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes differ in {} runs", self.bytes, self.runs)
+    }
+}
+
+/// The `(offset, length)` of each run of differing bytes between `a` and `b`.
+/// If one image is shorter, every byte past its end counts as differing.
+pub fn diff_images(a: &[u8], b: &[u8]) -> Vec<(u32, usize)> {
+    let length = a.len().max(b.len());
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+
+    for i in 0..length {
+        if a.get(i) == b.get(i) {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((offset, count)) if *offset as usize + *count == i => *count += 1,
+            _ => runs.push((i as u32, 1)),
+        }
+    }
+
+    runs
+}
+
+/// Totals the runs returned by `diff_images`
+pub fn summarize_diff(runs: &[(u32, usize)]) -> DiffSummary {
+    DiffSummary {
+        bytes: runs.iter().map(|(_, length)| length).sum(),
+        runs: runs.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_images() {
+        let image = [0x12, 0x34, 0xFF, 0xFF];
+
+        assert!(diff_images(&image, &image).is_empty());
+        assert!(diff_images(&[], &[]).is_empty());
+        assert_eq!(summarize_diff(&[]), DiffSummary::default());
+    }
+
+    #[test]
+    fn separate_and_adjacent_runs() {
+        let a = [0, 1, 2, 3, 4, 5, 6];
+        let b = [0, 9, 9, 3, 9, 5, 9];
+
+        let runs = diff_images(&a, &b);
+        assert_eq!(runs, [(1, 2), (4, 1), (6, 1)]);
+        assert_eq!(summarize_diff(&runs), DiffSummary { bytes: 4, runs: 3 });
+    }
+
+    #[test]
+    fn longer_image_differs_past_the_shorter() {
+        assert_eq!(diff_images(&[1, 2, 3], &[1, 2, 3, 4, 5]), [(3, 2)]);
+        assert_eq!(diff_images(&[1, 2, 3, 4, 5], &[1, 2, 3]), [(3, 2)]);
+        assert_eq!(diff_images(&[], &[0xFF]), [(0, 1)]);
+    }
+
+    #[test]
+    fn difference_runs_into_the_longer_tail() {
+        let runs = diff_images(&[1, 2, 3, 4], &[1, 9]);
+
+        assert_eq!(runs, [(1, 3)]);
+        assert_eq!(summarize_diff(&runs), DiffSummary { bytes: 3, runs: 1 });
+    }
+}
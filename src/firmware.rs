@@ -3,18 +3,35 @@
 // (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
 // notice may not be copied, modified, or distributed except according to those terms.
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use snafu::{Backtrace, ResultExt, Snafu};
+#[cfg(feature = "gzip")]
+use flate2::read::GzDecoder;
+#[cfg(feature = "sha2")]
+use sha2::{Digest, Sha256};
+use snafu::{Backtrace, IntoError, OptionExt, ResultExt, Snafu};
 use tempfile::TempPath;
+#[cfg(feature = "zerocopy")]
+use zerocopy::FromBytes;
 
-use dss::com::ti::debug::engine::scripting::{Memory, Register};
+use dss::com::ti::debug::engine::scripting::{Memory, Register, Target};
 
 use crate::assets;
-use crate::types::{Device, SpiPin, SpiPins};
-use crate::xflash::Xflash;
+use crate::metadata::FlashHeader;
+use crate::stats::{PollStats, Stats, StatsCounters};
+use crate::types::{Device, DeviceFamily, Endianness, SpiPin, SpiPins};
+use crate::xflash::{Xflash, SECTOR_SIZE};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -30,271 +47,2291 @@ pub enum Error {
         bytes: [u32; 4],
         backtrace: Backtrace,
     },
-    #[snafu(display("Bad response received from firmware: {:?}", response))]
+    #[snafu(display(
+        "Bad response received from firmware: {:?} (kind 0x{:X})",
+        response,
+        response.kind()
+    ))]
     BadResponse {
         response: Response,
         backtrace: Backtrace,
     },
     #[snafu(display("An error response received from firmware with value: {}", kind))]
     ErrorResponse { kind: u32, backtrace: Backtrace },
+    #[snafu(display(
+        "Address range at offset 0x{:X} with length {} exceeds the 32-bit address space",
+        offset,
+        length
+    ))]
+    AddressOverflow {
+        offset: u32,
+        length: usize,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "{} pages from page {} exceed the 32-bit address space",
+        count,
+        start_page
+    ))]
+    PageOverflow {
+        start_page: u32,
+        count: u32,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Length {} is not a multiple of 4 bytes", length))]
+    UnalignedLength { length: u32, backtrace: Backtrace },
+    #[snafu(display("Verification of written data failed"))]
+    VerificationFailed { backtrace: Backtrace },
+    #[snafu(display("Operation timed out after {} bytes were transferred", bytes_done))]
+    TotalTimeout {
+        bytes_done: usize,
+        backtrace: Backtrace,
+    },
     #[snafu(display("Tool timed out waiting for a response from firmware"))]
     FirmwareTimeout { backtrace: Backtrace },
+    #[snafu(display(
+        "Timed out waiting for the firmware to {} {:?}",
+        if *command_acked { "complete" } else { "take" },
+        command
+    ))]
+    ResponseTimeout {
+        command: Command,
+        /// Whether the firmware took the command off the doorbell. If it did
+        /// the firmware hung while executing it, otherwise the command never
+        /// reached the firmware.
+        command_acked: bool,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Read failed at offset 0x{:X} after {} bytes in {:?}: {}",
+        offset,
+        data.len(),
+        elapsed,
+        source
+    ))]
+    PartialRead {
+        data: Vec<u8>,
+        offset: u32,
+        elapsed: Duration,
+        source: Box<Error>,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Write stalled at offset 0x{:X} after {} bytes in {:?}: {}",
+        offset,
+        bytes_done,
+        elapsed,
+        source
+    ))]
+    WriteStalled {
+        offset: u32,
+        bytes_done: usize,
+        elapsed: Duration,
+        source: Box<Error>,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Range at 0x{:X} of length {} exceeds the flash capacity of {} bytes",
+        offset,
+        length,
+        capacity
+    ))]
+    BeyondCapacity {
+        offset: u32,
+        length: u32,
+        capacity: u32,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Tail of length {} is longer than the flash capacity of {} bytes",
+        length,
+        capacity
+    ))]
+    TailBeyondCapacity {
+        length: u32,
+        capacity: u32,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Firmware entry outside of SRAM: stack 0x{:X}, reset handler 0x{:X}",
+        stack_addr,
+        reset_isr
+    ))]
+    InvalidEntry {
+        stack_addr: u32,
+        reset_isr: u32,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Firmware did not become idle within {:?}", timeout))]
+    CommandAckTimeout {
+        timeout: Duration,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Reset vector 0x{:08X} doesn't have the Thumb bit set, the firmware may be loaded at the wrong address",
+        value
+    ))]
+    InvalidResetVector { value: u32, backtrace: Backtrace },
+    #[snafu(display("Invalid vector table read width: {}", width))]
+    InvalidReadWidth { width: u8, backtrace: Backtrace },
+    #[snafu(display("The firmware binary read back different from what was loaded"))]
+    LoadVerifyFailed { backtrace: Backtrace },
+    #[snafu(display("Pattern not found within {} bytes", max))]
+    PatternNotFound { max: u32, backtrace: Backtrace },
+    #[snafu(display("Write target is not erased at offset 0x{:X}", offset))]
+    WriteToNonBlank { offset: u32, backtrace: Backtrace },
+    #[snafu(display("No device given to build the firmware for"))]
+    MissingDevice { backtrace: Backtrace },
+    #[snafu(display("Invalid firmware memory layout: {:?}", layout))]
+    InvalidLayout {
+        layout: MemoryLayout,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("SPI pins given without injecting the firmware"))]
+    SpiPinsWithoutInject { backtrace: Backtrace },
+    #[snafu(display("Chunk size must be greater than zero"))]
+    ZeroChunkSize { backtrace: Backtrace },
+    #[snafu(display("Operation was cancelled"))]
+    Cancelled { backtrace: Backtrace },
+    #[snafu(display("No external flash detected"))]
+    NoFlashDetected { backtrace: Backtrace },
+    #[snafu(display("Erased flash is not blank at offset 0x{:X}", offset))]
+    EraseVerifyFailed { offset: u32, backtrace: Backtrace },
+    #[snafu(display("Capacity of the external flash is unknown: {}", xflash))]
+    UnknownCapacity {
+        xflash: Xflash,
+        backtrace: Backtrace,
+    },
     #[snafu(display("Unable to create the firmware binary asset: {}", source))]
     FirmwareAsset {
         source: io::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display(
+        "Unable to write the firmware binary to temp directory {}: {}",
+        dir.display(),
+        source
+    ))]
+    TempDirUnwritable {
+        dir: PathBuf,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Unable to read the image to write: {}", source))]
+    ImageRead {
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Flash differs from the image at offset 0x{:X}", offset))]
+    ImageMismatch { offset: u32, backtrace: Backtrace },
+    #[snafu(display("Unable to open image file {}: {}", path.display(), source))]
+    ImageOpen {
+        path: PathBuf,
+        source: io::Error,
+        backtrace: Backtrace,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    GetXflashInfo,
+    SectorErase { offset: u32, length: u32 },
+    MassErase,
+    ReadBlock { offset: u32, length: u32 },
+    WriteBlock { offset: u32, length: u32 },
+}
+
+impl Command {
+    pub fn opcode(&self) -> u32 {
+        use Command::*;
+
+        match self {
+            GetXflashInfo => 0xC0,
+            SectorErase { .. } => 0xC1,
+            MassErase => 0xC2,
+            ReadBlock { .. } => 0xC3,
+            WriteBlock { .. } => 0xC4,
+        }
+    }
+
+    /// The doorbell words of the command. These are host integers, the DSS
+    /// layer stores 32-bit values in the byte order of the target, so no
+    /// byte swapping is needed here.
+    fn to_bytes(&self) -> [u32; 4] {
+        use Command::*;
+
+        let opcode = self.opcode();
+
+        match self {
+            GetXflashInfo | MassErase => [opcode, 0, 0, 0],
+            SectorErase { offset, length }
+            | ReadBlock { offset, length }
+            | WriteBlock { offset, length } => [opcode, *offset, *length, 0],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Response {
+    Ok,
+    XflashInfo(Xflash),
+}
+
+impl Response {
+    const OK_KIND: u32 = 0xD0;
+    const XFLASHINFO_KIND: u32 = 0xD1;
+
+    pub fn kind(&self) -> u32 {
+        match self {
+            Response::Ok => Response::OK_KIND,
+            Response::XflashInfo(_) => Response::XFLASHINFO_KIND,
+        }
+    }
+
+    /// Parses the doorbell response words, which DSS reads as host integers
+    fn from_bytes(bytes: &[u32; 4]) -> Result<Self> {
+        const OK_VAL: u32 = Response::OK_KIND;
+        const XFLASHINFO_VAL: u32 = Response::XFLASHINFO_KIND;
+
+        let rsp = match bytes {
+            [OK_VAL, 0, 0, 0] => Response::Ok,
+            [XFLASHINFO_VAL, mid, did, 0] => Response::XflashInfo(Xflash::from_id(*mid, *did)),
+            _ => InvalidResponse { bytes: *bytes }.fail()?,
+        };
+        Ok(rsp)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EraseMode {
+    /// Assume the range is already erased
+    None,
+    /// Erase the sectors touched by the range
+    Range,
+    /// Erase the entire flash, the fastest way to write a full image
+    Mass,
+}
+
+impl Default for EraseMode {
+    fn default() -> Self {
+        EraseMode::Range
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EraseState {
+    InProgress,
+    Done,
+    Failed { kind: u32 },
+}
+
+/// The raw doorbell words, see `Firmware::doorbell_snapshot`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DoorbellState {
+    pub cmd_kind: u32,
+    pub cmd_arg0: u32,
+    pub cmd_arg1: u32,
+    pub cmd_arg2: u32,
+    pub rsp_kind: u32,
+    pub rsp_val0: u32,
+    pub rsp_val1: u32,
+    pub rsp_val2: u32,
+}
+
+/// How a SPI line looks from the data read over it, see `Firmware::self_test`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinHealth {
+    Ok,
+    StuckLow,
+    StuckHigh,
+    Inconclusive,
+}
+
+/// The result of `Firmware::self_test`
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTestReport {
+    pub xflash: Xflash,
+    /// Whether the first page read the same twice. A flaky CLK or CSN
+    /// connection tends to give different data.
+    pub data_stable: bool,
+    pub miso: PinHealth,
+}
+
+impl SelfTestReport {
+    pub fn is_healthy(&self) -> bool {
+        self.data_stable && self.miso == PinHealth::Ok
+    }
+}
+
+/// A copy of a flash range which can be read by flash address, see
+/// `Firmware::snapshot`
+#[derive(Clone, Debug)]
+pub struct FlashSnapshot {
+    pub base: u32,
+    pub data: Vec<u8>,
+}
+
+impl FlashSnapshot {
+    /// The byte at flash address `addr`, or `None` outside the captured range
+    pub fn read(&self, addr: u32) -> Option<u8> {
+        let index = addr.checked_sub(self.base)?;
+        self.data.get(index as usize).copied()
+    }
+}
+
+/// The result of `Firmware::endurance_test`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnduranceReport {
+    /// Number of erase and program cycles which verified
+    pub cycles_passed: u32,
+    /// The first failure, which ends the test
+    pub failure: Option<EnduranceFailure>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnduranceFailure {
+    /// A byte at `offset` wasn't 0xFF after erasing in cycle `cycle`
+    Erase { cycle: u32, offset: u32 },
+    /// A byte at `offset` read back wrong after programming in cycle `cycle`
+    Program { cycle: u32, offset: u32 },
+}
+
+/// An operation to estimate the duration of with `Firmware::estimate_duration`
+#[derive(Clone, Copy, Debug)]
+pub enum EstimatedOp {
+    Read { length: u32 },
+    Write { length: u32 },
+    SectorErase { length: u32 },
+    MassErase { capacity: u32 },
+}
+
+// Number of words at the start of the binary read back after loading it
+const LOAD_CHECK_WORDS: u32 = 8;
+
+// Offsets into the vector table at the start of SRAM
+const STACK_ADDR: u32 = 0x00;
+const RESET_ISR: u32 = 0x04;
+
+// Offsets into the conf region
+const CONF_VALID: u32 = 0x00;
+const CONF_SPI_MISO: u32 = 0x04;
+const CONF_SPI_MOSI: u32 = 0x08;
+const CONF_SPI_CLK: u32 = 0x0C;
+const CONF_SPI_CSN: u32 = 0x10;
+
+// Value `inject` writes to CONF_VALID along with the SPI pins. Other non-zero
+// values are taken as leftover SRAM contents rather than a config.
+const CONF_VALID_MARKER: u32 = 1;
+
+// Offsets into the doorbell region
+const DOORBELL_CMD_KIND: u32 = 0x00;
+const DOORBELL_CMD_ARG0: u32 = 0x04;
+const DOORBELL_CMD_ARG1: u32 = 0x08;
+const DOORBELL_CMD_ARG2: u32 = 0x0C;
+
+const DOORBELL_RSP_KIND: u32 = 0x10;
+const DOORBELL_RSP_VAL0: u32 = 0x14;
+const DOORBELL_RSP_VAL1: u32 = 0x18;
+const DOORBELL_RSP_VAL2: u32 = 0x1C;
+// Number of consecutive response words from DOORBELL_RSP_KIND
+const DOORBELL_RSP_WORDS: u32 = 4;
+
+// NVIC interrupt clear-enable and clear-pending registers. The CC13xx and
+// CC26xx devices have fewer than 64 interrupts, so two of each is enough.
+const NVIC_ICER0: u32 = 0xE000_E180;
+const NVIC_ICER1: u32 = 0xE000_E184;
+const NVIC_ICPR0: u32 = 0xE000_E280;
+const NVIC_ICPR1: u32 = 0xE000_E284;
+
+/// Program page size of all the known flashes
+pub const PAGE_SIZE: u32 = 0x100;
+
+const DWELL_TIME: Duration = Duration::from_millis(100);
+
+// How long `read_data_skipping` waits for the firmware to finish a block
+// which timed out before moving on to the next
+const SKIP_IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Typical timings of the supported flashes and the firmware SPI clock, used
+// for duration estimates only
+const SPI_BIT_RATE: u64 = 4_000_000;
+const PAGE_PROGRAM_TIME: Duration = Duration::from_micros(850);
+const SECTOR_ERASE_TIME: Duration = Duration::from_millis(40);
+
+/// SRAM addresses the firmware is linked against. Must match the
+/// `firmware.lds` linker script of the corresponding device family.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryLayout {
+    pub sram_start: u32,
+    pub conf_start: u32,
+    pub doorbell_start: u32,
+    pub buf_start: u32,
+    pub buf_size: u32,
+}
+
+impl From<DeviceFamily> for MemoryLayout {
+    fn from(device_family: DeviceFamily) -> Self {
+        use DeviceFamily::*;
+
+        // Fits in the 20 KB of SRAM on the smallest devices
+        const SMALL_SRAM: MemoryLayout = MemoryLayout {
+            sram_start: 0x2000_0000,
+            conf_start: 0x2000_3000,
+            doorbell_start: 0x2000_3100,
+            buf_start: 0x2000_4000,
+            buf_size: 0x1000,
+        };
+
+        match device_family {
+            CC13x0 | CC26x0 | CC26x0R2 | CC13x2_CC26x2 => SMALL_SRAM,
+        }
+    }
+}
+
+/// Builds a `Firmware` with more options than `Firmware::new`, which is
+/// the same as only giving the device and config.
+#[derive(Clone, Debug, Default)]
+pub struct FirmwareBuilder {
+    device: Option<Device>,
+    config: FirmwareConfig,
+    layout: Option<MemoryLayout>,
+    spi_pins: Option<SpiPins>,
+    inject: bool,
+}
+
+impl FirmwareBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn device(mut self, device: Device) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    pub fn config(mut self, config: FirmwareConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Overrides the memory layout of the device family, for firmware
+    /// linked differently
+    pub fn layout(mut self, layout: MemoryLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// SPI pins to inject the firmware with, requires `inject`
+    pub fn spi_pins(mut self, spi_pins: SpiPins) -> Self {
+        self.spi_pins = Some(spi_pins);
+        self
+    }
+
+    /// Whether `build` also injects the firmware
+    pub fn inject(mut self, inject: bool) -> Self {
+        self.inject = inject;
+        self
+    }
+
+    pub fn build<'a>(self, memory: Memory<'a>, target: Target<'a>) -> Result<Firmware<'a>> {
+        let device = self.device.context(MissingDevice {})?;
+        let layout = self
+            .layout
+            .unwrap_or_else(|| DeviceFamily::from(device).into());
+
+        // The regions must follow each other in SRAM in this order
+        ensure!(
+            layout.sram_start < layout.conf_start
+                && layout.conf_start < layout.doorbell_start
+                && layout.doorbell_start < layout.buf_start
+                && layout.buf_size > 0
+                && layout.buf_start.checked_add(layout.buf_size).is_some(),
+            InvalidLayout { layout }
+        );
+        ensure!(
+            self.spi_pins.is_none() || self.inject,
+            SpiPinsWithoutInject {}
+        );
+
+        let firmware = Firmware::with_layout(memory, target, device, layout, self.config)?;
+
+        if self.inject {
+            firmware.inject(self.spi_pins)?;
+        }
+
+        Ok(firmware)
+    }
+}
+
+/// Blocks the calling thread while the host waits on the firmware, e.g. in
+/// between polls of the doorbell.
+pub trait Waiter: fmt::Debug {
+    fn wait(&self, duration: Duration);
+
+    /// Called in between the blocks of a read or write, letting an embedder
+    /// run other work. This doesn't make the operations async, the transfer
+    /// only continues once it returns. Does nothing by default.
+    fn yield_now(&self) {}
+}
+
+/// The default `Waiter`, which sleeps the thread
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSleep;
+
+impl Waiter for ThreadSleep {
+    fn wait(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Receives progress of long running operations
+pub trait ProgressSink {
+    /// Called periodically while the operation runs but how far it got is
+    /// unknown, with the time since it started. Does nothing by default.
+    fn tick(&mut self, _elapsed: Duration) {}
+
+    /// Called with how far the operation got, from 0 to 100. Does nothing by
+    /// default.
+    fn percent(&mut self, _percent: u8) {}
+}
+
+/// Discards all progress
+impl ProgressSink for () {}
+
+#[derive(Clone, Debug)]
+pub struct FirmwareConfig {
+    /// Time to sleep between consecutive `WriteBlock` commands. The firmware
+    /// already polls the WIP bit before each page program, so this is only
+    /// needed for flashes that report ready before they can accept the next
+    /// page. Zero disables pacing.
+    pub write_pacing: Duration,
+    /// Number of firmware buffer sized blocks to keep cached on the host, so
+    /// repeated reads of the same region skip the doorbell round-trip. Blocks
+    /// are invalidated when written or erased. Zero disables the cache.
+    pub read_cache_blocks: usize,
+    /// Upper bound on the total time of a single read or write, checked
+    /// between blocks. `None` lets an operation run for as long as the
+    /// blocks keep completing.
+    pub total_timeout: Option<Duration>,
+    /// Number of times `program` re-erases and rewrites sectors which fail
+    /// verification before giving up. Rewriting without erasing can't fix a
+    /// failed write, so no retries are done with `EraseMode::None`.
+    pub verify_retries: u8,
+    /// Directory to write the firmware binary to before loading it. `None`
+    /// uses the system temp directory, which honors `TMPDIR`.
+    pub temp_dir: Option<PathBuf>,
+    /// Whether `inject` disables and clears all NVIC interrupts and sets
+    /// PRIMASK before starting the firmware, so interrupts left enabled by
+    /// the application on the device can't fire during firmware startup.
+    pub mask_interrupts: bool,
+    /// Whether erases read back the erased range and check that it's all
+    /// 0xFF. For a mass erase this reads the whole flash, which must be a
+    /// known one.
+    pub verify_blank: bool,
+    /// Whether `program` checks that a flash responds before erasing, so an
+    /// empty footprint fails right away instead of on an erase timeout.
+    /// Disable for flashes which don't identify reliably.
+    pub presence_check: bool,
+    /// Whether `inject` halts the core first. The core must be halted for
+    /// the firmware to be loaded.
+    pub halt_before_inject: bool,
+    /// Whether `inject` without SPI pins keeps the pins of a previous
+    /// injection. Loading the binary resets the config words, so pins marked
+    /// valid by `inject` are read before loading and written back after.
+    /// Explicitly given pins take precedence.
+    pub preserve_config: bool,
+    /// Whether writes read each block before programming it, and fail if it
+    /// isn't all 0xFF. Programming can only clear bits, so writing over
+    /// data that isn't erased corrupts it. This also rejects `write_aligned`
+    /// over programmed bytes next to the range, and costs a read per block.
+    pub require_erased_before_write: bool,
+    /// Access width in bits, 8, 16 or 32, that `inject` reads the initial
+    /// stack pointer and reset handler from the vector table with. Only
+    /// needs changing to work around DSS quirks with 32-bit reads.
+    pub vector_read_width: u8,
+    /// Set from another thread to stop waiting for a mass erase. The flash
+    /// keeps erasing, and the firmware only takes new commands once it's done.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Initial `(stack pointer, reset handler)` for firmware whose vector
+    /// table isn't at the start of SRAM. `None` reads them from the vector
+    /// table. Both must point into the firmware's SRAM region.
+    pub entry: Option<(u32, u32)>,
+    /// How to wait in between polls of the firmware and for write pacing.
+    /// Embedders can supply a wait which yields to their event loop.
+    pub waiter: Arc<dyn Waiter>,
+}
+
+impl Default for FirmwareConfig {
+    fn default() -> Self {
+        Self {
+            write_pacing: Duration::default(),
+            read_cache_blocks: 0,
+            total_timeout: None,
+            verify_retries: 0,
+            temp_dir: None,
+            mask_interrupts: true,
+            verify_blank: false,
+            presence_check: true,
+            halt_before_inject: false,
+            preserve_config: false,
+            require_erased_before_write: false,
+            vector_read_width: 32,
+            cancel: None,
+            entry: None,
+            waiter: Arc::new(ThreadSleep),
+        }
+    }
+}
+
+/// Whether `length` bytes from `offset` can be transferred. The chunk loops
+/// advance the offset to the end of the range, so `offset + length` must
+/// itself fit in a u32.
+pub fn in_address_range(offset: u32, length: usize) -> bool {
+    u64::from(offset) + length as u64 <= u64::from(u32::MAX)
+}
+
+fn ensure_address_range(offset: u32, length: usize) -> Result<()> {
+    ensure!(
+        in_address_range(offset, length),
+        AddressOverflow { offset, length }
+    );
+    Ok(())
+}
+
+fn div_ceil(value: u32, divisor: u32) -> u32 {
+    value / divisor + u32::from(value % divisor != 0)
+}
+
+/// `length` bytes of `word` repeated in the given byte order
+fn fill_pattern(word: u32, endianness: Endianness, length: u32) -> Vec<u8> {
+    let bytes = match endianness {
+        Endianness::Little => word.to_le_bytes(),
+        Endianness::Big => word.to_be_bytes(),
+    };
+    bytes.iter().copied().cycle().take(length as _).collect()
+}
+
+/// Calls `identify` up to `attempts` times, and `before_retry` in between,
+/// see `Firmware::get_xflash_info_retry`
+fn retry_identify(
+    attempts: u8,
+    mut identify: impl FnMut() -> Result<Xflash>,
+    mut before_retry: impl FnMut() -> Result<()>,
+) -> Result<Xflash> {
+    let attempts = cmp::max(attempts, 1);
+
+    for attempt in 1..=attempts {
+        let last = attempt == attempts;
+
+        match identify() {
+            Ok(xflash) if !xflash.is_zero_id() || last => return Ok(xflash),
+            Err(err @ Error::InvalidResponse { .. }) if last => return Err(err),
+            Ok(_) | Err(Error::InvalidResponse { .. }) => {}
+            Err(err) => return Err(err),
+        }
+
+        before_retry()?;
+    }
+
+    unreachable!("the last attempt always returns")
+}
+
+/// The doorbell offsets and words to write to post `command`, in the order
+/// they must be written. The firmware starts on a non-zero kind, so the
+/// arguments must all be in place before the kind is written.
+fn doorbell_writes(command: Command) -> [(u32, u32); 4] {
+    let bytes = command.to_bytes();
+
+    [
+        (DOORBELL_CMD_ARG2, bytes[3]),
+        (DOORBELL_CMD_ARG1, bytes[2]),
+        (DOORBELL_CMD_ARG0, bytes[1]),
+        (DOORBELL_CMD_KIND, bytes[0]),
+    ]
+}
+
+/// The `(offset, length)` of `count` pages from page number `start_page`, or
+/// `None` if they don't fit in the 32-bit address space
+fn page_range(start_page: u32, count: u32) -> Option<(u32, u32)> {
+    let offset = start_page.checked_mul(PAGE_SIZE)?;
+    let length = count.checked_mul(PAGE_SIZE)?;
+
+    if in_address_range(offset, length as _) {
+        Some((offset, length))
+    } else {
+        None
+    }
+}
+
+/// Reads into the whole of `buf` unless `reader` runs dry, as short reads
+/// are common for decompressors and pipes. Returns the number of bytes read.
+fn read_full(reader: &mut impl io::Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err).context(ImageRead {}),
+        }
+    }
+    Ok(filled)
+}
+
+/// Start addresses of the sectors where `read_back` differs from `data`
+fn mismatched_sectors(offset: u32, data: &[u8], read_back: &[u8]) -> Vec<u32> {
+    let mut sectors: Vec<u32> = data
+        .iter()
+        .zip(read_back)
+        .enumerate()
+        .filter(|(_, (expected, actual))| expected != actual)
+        .map(|(i, _)| {
+            let address = offset + i as u32;
+            address - address % SECTOR_SIZE
+        })
+        .collect();
+    sectors.dedup();
+    sectors
+}
+
+/// The sectors the firmware erases for a `SectorErase` of `length` bytes at
+/// `offset`, as the start address and length of the erased span. This
+/// follows the sector count of `Xflash::erase` in the firmware, 32-bit
+/// wrapping included, which leaves out the last sector touched by the range
+/// if the range ends on its first byte.
+fn erased_span(offset: u32, length: u32) -> (u32, u32) {
+    let last_address = offset.wrapping_add(length).wrapping_sub(1);
+    let first_address = offset - offset % SECTOR_SIZE;
+    let num_sectors = last_address
+        .wrapping_sub(first_address)
+        .wrapping_add(SECTOR_SIZE - 1)
+        / SECTOR_SIZE;
+
+    (first_address, num_sectors * SECTOR_SIZE)
+}
+
+pub struct Firmware<'a> {
+    memory: Memory<'a>,
+    target: Target<'a>,
+    binary: TempPath,
+    // The first words of the binary, to check the load against
+    binary_head: Vec<u32>,
+    config: FirmwareConfig,
+    layout: MemoryLayout,
+    // Least recently used block first
+    read_cache: RefCell<Vec<(u32, Vec<u8>)>>,
+    stats: StatsCounters,
+    // Number of commands and their total round-trip time
+    command_time: Cell<(u32, Duration)>,
+    // Whether a command was posted and its response not yet taken
+    in_flight: Cell<bool>,
 }
 
-pub type Result<T, E = Error> = std::result::Result<T, E>;
+impl<'a> Firmware<'a> {
+    pub fn new(
+        memory: Memory<'a>,
+        target: Target<'a>,
+        device: Device,
+        config: FirmwareConfig,
+    ) -> Result<Firmware<'a>> {
+        let layout = DeviceFamily::from(device).into();
+        Firmware::with_layout(memory, target, device, layout, config)
+    }
+
+    fn with_layout(
+        memory: Memory<'a>,
+        target: Target<'a>,
+        device: Device,
+        layout: MemoryLayout,
+        config: FirmwareConfig,
+    ) -> Result<Firmware<'a>> {
+        let (binary, binary_head) =
+            Firmware::create_firmware_binary(device, config.temp_dir.as_deref())?;
+
+        Ok(Self {
+            memory,
+            target,
+            binary,
+            binary_head,
+            config,
+            layout,
+            read_cache: RefCell::new(Vec::new()),
+            stats: StatsCounters::default(),
+            command_time: Cell::new((0, Duration::default())),
+            in_flight: Cell::new(false),
+        })
+    }
+
+    pub fn config(&self) -> &FirmwareConfig {
+        &self.config
+    }
+
+    /// Replaces the config, taking effect from the next command. Cached
+    /// blocks beyond a smaller `read_cache_blocks` are dropped, least
+    /// recently used first.
+    pub fn set_config(&mut self, config: FirmwareConfig) {
+        let cache = self.read_cache.get_mut();
+        let excess = cache.len().saturating_sub(config.read_cache_blocks);
+        cache.drain(..excess);
+
+        self.config = config;
+    }
+
+    /// Halts the core, unless it's already halted
+    pub fn halt(&self) -> Result<()> {
+        if !self.target.is_halted().context(DssError {})? {
+            self.target.halt().context(DssError {})?;
+        }
+        Ok(())
+    }
+
+    /// Lets the core run, without waiting for it to halt again
+    pub fn resume(&self) -> Result<()> {
+        self.target.run_asynch().context(DssError {})?;
+        Ok(())
+    }
+
+    pub fn inject(&self, spi_pins: Option<SpiPins>) -> Result<()> {
+        let width = self.config.vector_read_width;
+        ensure!([8, 16, 32].contains(&width), InvalidReadWidth { width });
+
+        if self.config.halt_before_inject {
+            self.halt()?;
+        }
+
+        let preserved = match spi_pins {
+            None if self.config.preserve_config => self.injected_pins()?,
+            _ => None,
+        };
+
+        let binary_path = self.binary.to_string_lossy().to_owned();
+
+        self.dss_load_raw(&binary_path)?;
+
+        // DSS has been seen to report success without loading anything, which
+        // would otherwise only show as a timeout on the first command
+        let loaded = self.dss_read_words(self.layout.sram_start, self.binary_head.len() as _)?;
+        ensure!(loaded == self.binary_head, LoadVerifyFailed {});
+
+        if let Some(spi_pins) = spi_pins.or(preserved) {
+            self.dss_write_data(self.conf(CONF_VALID), CONF_VALID_MARKER)?;
+            self.dss_write_data(self.conf(CONF_SPI_MISO), spi_pins[SpiPin::Miso] as _)?;
+            self.dss_write_data(self.conf(CONF_SPI_MOSI), spi_pins[SpiPin::Mosi] as _)?;
+            self.dss_write_data(self.conf(CONF_SPI_CLK), spi_pins[SpiPin::Clk] as _)?;
+            self.dss_write_data(self.conf(CONF_SPI_CSN), spi_pins[SpiPin::Csn] as _)?;
+        }
+
+        if self.config.mask_interrupts {
+            self.mask_interrupts()?;
+        }
+
+        let (stack_addr, reset_isr) = match self.config.entry {
+            Some((stack_addr, reset_isr)) => {
+                // The stack grows down, so it may start at the very end
+                let sram_end = self.layout.buf_start + self.layout.buf_size;
+                ensure!(
+                    (self.layout.sram_start..=sram_end).contains(&stack_addr)
+                        && (self.layout.sram_start..sram_end).contains(&reset_isr),
+                    InvalidEntry {
+                        stack_addr,
+                        reset_isr
+                    }
+                );
+                (stack_addr, reset_isr)
+            }
+            None => (
+                self.dss_read_vector(self.layout.sram_start + STACK_ADDR)?,
+                self.dss_read_vector(self.layout.sram_start + RESET_ISR)?,
+            ),
+        };
+
+        // Cortex-M only runs Thumb code, so a handler address without the
+        // Thumb bit usually means the binary was loaded at the wrong address
+        ensure!(reset_isr & 1 == 1, InvalidResetVector { value: reset_isr });
+
+        self.dss_write_register(Register::MSP, stack_addr)?;
+        self.dss_write_register(Register::PC, reset_isr)?;
+        self.dss_write_register(Register::LR, 0xFFFF_FFFF)?;
+
+        Ok(())
+    }
+
+    /// Writes all ones to NVIC ICER0-1 and ICPR0-1 to disable and clear every
+    /// device interrupt, then sets PRIMASK. The firmware clears PRIMASK again
+    /// once it's ready to take its own interrupts.
+    fn mask_interrupts(&self) -> Result<()> {
+        for &register in &[NVIC_ICER0, NVIC_ICER1, NVIC_ICPR0, NVIC_ICPR1] {
+            self.dss_write_data(register, 0xFFFF_FFFF)?;
+        }
+        self.dss_write_register(Register::PRIMASK, 1)?;
+
+        Ok(())
+    }
+
+    /// Reads all doorbell words in one go without changing them, for
+    /// debugging protocol stalls
+    pub fn doorbell_snapshot(&self) -> Result<DoorbellState> {
+        let words = self.dss_read_words(self.doorbell(DOORBELL_CMD_KIND), 8)?;
+        ensure!(words.len() == 8, NoResponse {});
+
+        let word = |offset: u32| words[(offset / 4) as usize];
+
+        Ok(DoorbellState {
+            cmd_kind: word(DOORBELL_CMD_KIND),
+            cmd_arg0: word(DOORBELL_CMD_ARG0),
+            cmd_arg1: word(DOORBELL_CMD_ARG1),
+            cmd_arg2: word(DOORBELL_CMD_ARG2),
+            rsp_kind: word(DOORBELL_RSP_KIND),
+            rsp_val0: word(DOORBELL_RSP_VAL0),
+            rsp_val1: word(DOORBELL_RSP_VAL1),
+            rsp_val2: word(DOORBELL_RSP_VAL2),
+        })
+    }
+
+    /// Reads back the SPI pins written to the firmware config by `inject`, or
+    /// `None` if the firmware uses its default pins.
+    pub fn injected_pins(&self) -> Result<Option<SpiPins>> {
+        if self.dss_read_data(self.conf(CONF_VALID))? != CONF_VALID_MARKER {
+            return Ok(None);
+        }
+
+        let spi_pins = SpiPins([
+            self.dss_read_data(self.conf(CONF_SPI_MISO))? as _,
+            self.dss_read_data(self.conf(CONF_SPI_MOSI))? as _,
+            self.dss_read_data(self.conf(CONF_SPI_CLK))? as _,
+            self.dss_read_data(self.conf(CONF_SPI_CSN))? as _,
+        ]);
+
+        Ok(Some(spi_pins))
+    }
+
+    /// Average round-trip time of the commands sent so far, which includes
+    /// the DSS latency and the polling interval. Before any command is sent
+    /// the polling interval is used as a baseline.
+    pub fn link_latency(&self) -> Duration {
+        match self.command_time.get() {
+            (0, _) => DWELL_TIME,
+            (count, total) => total / count,
+        }
+    }
+
+    /// Estimates how long `op` takes without talking to the device. The
+    /// estimate adds one `link_latency` per command to the SPI transfer time
+    /// at the firmware clock rate and typical page program and sector erase
+    /// times of the supported flashes. A mass erase is estimated as erasing
+    /// every sector, which is the upper end of what the flashes specify for a
+    /// chip erase. Write pacing is included, a total timeout is not.
+    pub fn estimate_duration(&self, op: EstimatedOp) -> Duration {
+        let latency = self.link_latency();
+        let blocks = |length: u32| div_ceil(length, self.layout.buf_size);
+        let transfer =
+            |length: u32| Duration::from_micros(u64::from(length) * 8 * 1_000_000 / SPI_BIT_RATE);
+
+        match op {
+            EstimatedOp::Read { length } => blocks(length) * latency + transfer(length),
+            EstimatedOp::Write { length } => {
+                let blocks = blocks(length);
+                let pages = div_ceil(length, PAGE_SIZE);
+                blocks * latency
+                    + blocks.saturating_sub(1) * self.config.write_pacing
+                    + transfer(length)
+                    + pages * PAGE_PROGRAM_TIME
+            }
+            EstimatedOp::SectorErase { length } => {
+                let sectors = div_ceil(length, SECTOR_SIZE);
+                latency + sectors * SECTOR_ERASE_TIME
+            }
+            EstimatedOp::MassErase { capacity } => {
+                latency + (capacity / SECTOR_SIZE) * SECTOR_ERASE_TIME
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// Histogram of how many doorbell polls each command took, cleared by
+    /// `reset_stats`. Most commands finishing in the first bucket suggests
+    /// the polling interval could be longer, and the reverse.
+    pub fn poll_stats(&self) -> PollStats {
+        self.stats.poll_snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    pub fn get_xflash_info(&self) -> Result<Xflash> {
+        match self.send_command(Command::GetXflashInfo, None)? {
+            Response::XflashInfo(xflash) => Ok(xflash),
+            response => BadResponse { response }.fail(),
+        }
+    }
+
+    /// Like `get_xflash_info`, but makes up to `attempts` tries for flashes
+    /// that are slow to respond after power up. The firmware only reads the
+    /// ID when it starts, so before each retry this waits `delay` and injects
+    /// the firmware again with the same SPI pins, which wakes the flash and
+    /// reads its ID anew. Both an all-zero ID and an invalid response are
+    /// retried. The last error is returned if every try fails, while an ID
+    /// that stays all zero is returned as is.
+    pub fn get_xflash_info_retry(&self, attempts: u8, delay: Duration) -> Result<Xflash> {
+        retry_identify(
+            attempts,
+            || self.get_xflash_info(),
+            || {
+                StatsCounters::add(&self.stats.retries, 1);
+                self.config.waiter.wait(delay);
+                self.reinject()
+            },
+        )
+    }
+
+    /// Restarts the firmware with the SPI pins it currently runs with
+    fn reinject(&self) -> Result<()> {
+        let spi_pins = self.injected_pins()?;
+
+        self.halt()?;
+        self.inject(spi_pins)?;
+        self.resume()
+    }
+
+    /// Whether a flash responds on the SPI bus. With no flash the firmware
+    /// either fails to identify it or reads an ID of all zeros or all ones,
+    /// depending on how MISO floats.
+    pub fn is_present(&self) -> Result<bool> {
+        match self.get_xflash_info() {
+            Ok(xflash) => Ok(!xflash.is_zero_id() && !xflash.is_ones_id()),
+            Err(Error::InvalidResponse { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Checks the SPI wiring without writing to the flash. The firmware only
+    /// reads the flash ID when it starts, so the ID is checked once while the
+    /// first page is read twice. An ID of all zeros means MISO is stuck low,
+    /// and all ones that it's stuck high or CSN doesn't reach the flash. A
+    /// page of all zeros also points at MISO stuck low, while all ones is
+    /// inconclusive as that is what erased flash reads as.
+    pub fn self_test(&self) -> Result<SelfTestReport> {
+        let xflash = self.get_xflash_info()?;
+
+        let page = self.read_block(0, PAGE_SIZE, None)?;
+        let data_stable = self.read_block(0, PAGE_SIZE, None)? == page;
+
+        let miso = if xflash.is_zero_id() {
+            PinHealth::StuckLow
+        } else if xflash.is_ones_id() {
+            PinHealth::StuckHigh
+        } else if page.iter().all(|&b| b == 0x00) {
+            PinHealth::StuckLow
+        } else if !data_stable {
+            PinHealth::Inconclusive
+        } else {
+            PinHealth::Ok
+        };
+
+        Ok(SelfTestReport {
+            xflash,
+            data_stable,
+            miso,
+        })
+    }
+
+    pub fn sector_erase(&self, offset: u32, length: u32) -> Result<()> {
+        ensure_address_range(offset, length as _)?;
+
+        // Plus one for margin, as the write range can touch two sectors: one at
+        // the beginnning and one at the end
+        let num_sectors = length / SECTOR_SIZE + 1;
+        let timeout = num_sectors * Duration::from_millis(500);
+
+        let (first_address, erased_length) = erased_span(offset, length);
+        self.invalidate_read_cache(first_address, erased_length.into());
+
+        let command = Command::SectorErase { offset, length };
+        match self.send_command(command, Some(timeout))? {
+            Response::Ok => {}
+            response => BadResponse { response }.fail()?,
+        }
+
+        // Check every sector the firmware erased, not just the requested range
+        if self.config.verify_blank {
+            self.ensure_blank(first_address, erased_length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Issues a mass erase without waiting for it to complete. Completion must
+    /// be polled with `erase_poll`, and no other command may be sent before
+    /// the erase is done.
+    pub fn mass_erase_start(&self) -> Result<()> {
+        self.read_cache.borrow_mut().clear();
+        self.post_command(Command::MassErase)
+    }
+
+    pub fn erase_poll(&self) -> Result<EraseState> {
+        if self.dss_read_data(self.doorbell(DOORBELL_RSP_KIND))? == 0 {
+            return Ok(EraseState::InProgress);
+        }
+
+        let bytes = self.take_response()?;
+        match Response::from_bytes(&bytes) {
+            Ok(Response::Ok) => Ok(EraseState::Done),
+            Ok(response) => BadResponse { response }.fail(),
+            Err(_) => Ok(EraseState::Failed { kind: bytes[0] }),
+        }
+    }
+
+    /// Waits until the firmware has taken the last command and answered it,
+    /// discarding a response which was never taken, e.g. after a cancelled
+    /// mass erase. Afterwards a new command can be sent.
+    pub fn wait_idle(&self, timeout: Duration) -> Result<()> {
+        let start_time = Instant::now();
+
+        loop {
+            let cmd_kind = self.dss_read_data(self.doorbell(DOORBELL_CMD_KIND))?;
+
+            if cmd_kind == 0 && !self.in_flight.get() {
+                return Ok(());
+            }
+            if cmd_kind == 0 && self.dss_read_data(self.doorbell(DOORBELL_RSP_KIND))? != 0 {
+                self.take_response()?;
+                return Ok(());
+            }
+
+            ensure!(
+                start_time.elapsed() < timeout,
+                CommandAckTimeout { timeout }
+            );
+
+            self.config.waiter.wait(DWELL_TIME);
+        }
+    }
+
+    /// Blocking mass erase, built on `mass_erase_start` and `erase_poll`.
+    /// The waiter gets to yield and the cancel flag is checked on every poll.
+    /// The firmware can't report erase progress, so the wait is bounded by a
+    /// fixed timeout.
+    pub fn mass_erase(&self) -> Result<()> {
+        self.mass_erase_progress(&mut ())
+    }
+
+    /// Like `mass_erase`, but ticks `sink` on every poll while the erase runs
+    /// and reports 100% once it's done. The flash doesn't report how far it
+    /// got, so the ticks only tell that the erase is still alive.
+    pub fn mass_erase_progress(&self, sink: &mut dyn ProgressSink) -> Result<()> {
+        const TIMEOUT: Duration = Duration::from_secs(240);
+
+        self.mass_erase_start()?;
+
+        let sys_time = SystemTime::now();
+
+        loop {
+            match self.erase_poll()? {
+                EraseState::InProgress => sink.tick(sys_time.elapsed().unwrap_or_default()),
+                EraseState::Done => {
+                    sink.percent(100);
+                    break;
+                }
+                EraseState::Failed { kind } => return self.counted(ErrorResponse { kind }.fail()),
+            }
+
+            if sys_time.elapsed().unwrap_or_default() >= TIMEOUT {
+                return self.counted(FirmwareTimeout {}.fail());
+            }
+
+            self.config.waiter.yield_now();
+            if let Some(cancel) = &self.config.cancel {
+                ensure!(!cancel.load(Ordering::Relaxed), Cancelled {});
+            }
+
+            self.config.waiter.wait(DWELL_TIME);
+        }
+
+        if self.config.verify_blank {
+            self.ensure_blank(0, self.capacity()?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads back `length` bytes from `offset` and fails on the first byte
+    /// which isn't erased
+    fn ensure_blank(&self, offset: u32, length: u32) -> Result<()> {
+        ensure_address_range(offset, length as _)?;
+
+        let mut block_offset = offset;
+        let end = offset + length;
+
+        while block_offset < end {
+            let block_length = cmp::min(end - block_offset, self.layout.buf_size);
+            let values = self.read_data(block_offset, block_length)?;
+
+            if let Some(index) = values.iter().position(|&value| value != 0xFF) {
+                return EraseVerifyFailed {
+                    offset: block_offset + index as u32,
+                }
+                .fail();
+            }
+
+            block_offset += block_length;
+        }
+
+        Ok(())
+    }
+
+    /// Mass erases the flash, but keeps the data in each `(offset, length)`
+    /// range. The ranges are read to the host before the erase and written
+    /// back and verified after, so the operation takes a read, a write and a
+    /// second read of every range longer than a plain mass erase.
+    pub fn mass_erase_preserving(&self, ranges: &[(u32, u32)]) -> Result<()> {
+        let preserved = self.read_regions(ranges)?;
+
+        self.mass_erase()?;
+
+        for (&(offset, length), data) in ranges.iter().zip(&preserved) {
+            self.write_data(offset, data)?;
+
+            let read_back = self.read_data(offset, length)?;
+            ensure!(&read_back == data, VerificationFailed {});
+        }
+
+        Ok(())
+    }
+
+    pub fn read_data(&self, offset: u32, length: u32) -> Result<Vec<u8>> {
+        self.read_data_timeout(offset, length, None)
+    }
+
+    /// Like `read_data`, but waits up to `timeout` for each block instead of
+    /// the default command timeout when given.
+    pub fn read_data_timeout(
+        &self,
+        offset: u32,
+        length: u32,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        ensure_address_range(offset, length as _)?;
+
+        if self.config.read_cache_blocks > 0 {
+            return self.read_data_cached(offset, length, timeout);
+        }
+
+        let mut data = Vec::with_capacity(length as _);
+
+        let start_time = Instant::now();
+        let mut offset = offset;
+        let mut length = length;
+
+        // let mut zero_vec = Vec::with_capacity(BUF_SIZE as _);
+        // zero_vec.resize_with(BUF_SIZE as _, || 0);
+
+        while length > 0 {
+            if !data.is_empty() {
+                self.config.waiter.yield_now();
+            }
+
+            let ilength = std::cmp::min(length, self.layout.buf_size);
+
+            // self.dss_write_datas(BUF_START, &zero_vec)?;
+
+            // Hand back what was read so far, a failing flash may still have
+            // valuable data in the blocks before the failure
+            let values = match self
+                .ensure_total_time(start_time, data.len())
+                .and_then(|_| self.read_block(offset, ilength, timeout))
+            {
+                Ok(values) => values,
+                Err(err) => {
+                    let elapsed = start_time.elapsed();
+                    return Err(PartialRead {
+                        data,
+                        offset,
+                        elapsed,
+                    }
+                    .into_error(Box::new(err)));
+                }
+            };
+            data.extend_from_slice(&values);
+
+            offset += ilength;
+            length -= ilength;
+        }
+
+        Ok(data)
+    }
+
+    /// Like `read_data`, but a block the firmware fails to read is filled
+    /// with `fill` instead of failing the whole read, to recover as much as
+    /// possible from a degrading flash. Returns the data and the offsets of
+    /// the blocks that failed. DSS errors still abort, as nothing more can be
+    /// read without the debug link, and so does firmware which stays busy
+    /// after a block times out.
+    pub fn read_data_skipping(
+        &self,
+        offset: u32,
+        length: u32,
+        fill: u8,
+    ) -> Result<(Vec<u8>, Vec<u32>)> {
+        ensure_address_range(offset, length as _)?;
+
+        let mut data = Vec::with_capacity(length as _);
+        let mut failed = Vec::new();
+
+        let start_time = Instant::now();
+        let mut offset = offset;
+        let mut length = length;
+
+        while length > 0 {
+            if !data.is_empty() {
+                self.config.waiter.yield_now();
+            }
+
+            let ilength = std::cmp::min(length, self.layout.buf_size);
+
+            self.ensure_total_time(start_time, data.len())?;
+
+            match self.read_block(offset, ilength, None) {
+                Ok(values) => data.extend_from_slice(&values),
+                // The response was taken before it was parsed, so the
+                // firmware is ready for the next block
+                Err(Error::InvalidResponse { .. }) | Err(Error::BadResponse { .. }) => {
+                    data.resize(data.len() + ilength as usize, fill);
+                    failed.push(offset);
+                }
+                // The firmware may still be working on the block, so let it
+                // finish before sending the next one, or give up if it's hung
+                Err(Error::ResponseTimeout { .. }) => {
+                    self.wait_idle(SKIP_IDLE_TIMEOUT)?;
+                    data.resize(data.len() + ilength as usize, fill);
+                    failed.push(offset);
+                }
+                Err(err) => return Err(err),
+            }
+
+            offset += ilength;
+            length -= ilength;
+        }
+
+        Ok((data, failed))
+    }
+
+    /// Reads forward from `offset` one block at a time until `pattern` is
+    /// found, and returns the bytes up to and including it. Fails with
+    /// `PatternNotFound` if it doesn't end within `max` bytes. An empty
+    /// pattern matches right away.
+    pub fn read_until(&self, offset: u32, pattern: &[u8], max: u32) -> Result<Vec<u8>> {
+        if pattern.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        ensure_address_range(offset, max as _)?;
+
+        let mut data: Vec<u8> = Vec::new();
+
+        while (data.len() as u32) < max {
+            let ilength = cmp::min(max - data.len() as u32, self.layout.buf_size);
+
+            // Search from far enough back to catch a pattern straddling the
+            // previous block and this one
+            let search_start = data.len().saturating_sub(pattern.len() - 1);
+            data.extend_from_slice(&self.read_data(offset + data.len() as u32, ilength)?);
+
+            if let Some(i) = data[search_start..]
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+            {
+                data.truncate(search_start + i + pattern.len());
+                return Ok(data);
+            }
+        }
+
+        PatternNotFound { max }.fail()
+    }
+
+    /// Reads the last `length` bytes of the flash, in forward order. Only
+    /// known flashes have a capacity to count back from.
+    pub fn read_tail(&self, length: u32) -> Result<Vec<u8>> {
+        let capacity = self.capacity()?;
+        ensure!(length <= capacity, TailBeyondCapacity { length, capacity });
+
+        self.read_data(capacity - length, length)
+    }
+
+    /// Reads each `(offset, length)` region, returning their data in the same
+    /// order. All regions are checked against the flash capacity, if the
+    /// flash is a known one, before any is read.
+    pub fn read_regions(&self, regions: &[(u32, u32)]) -> Result<Vec<Vec<u8>>> {
+        let capacity = self.get_xflash_info()?.capacity();
+
+        for &(offset, length) in regions {
+            ensure_address_range(offset, length as _)?;
+
+            if let Some(capacity) = capacity {
+                ensure!(
+                    u64::from(offset) + u64::from(length) <= u64::from(capacity),
+                    BeyondCapacity {
+                        offset,
+                        length,
+                        capacity
+                    }
+                );
+            }
+        }
+
+        regions
+            .iter()
+            .map(|&(offset, length)| self.read_data(offset, length))
+            .collect()
+    }
+
+    /// Reads `length` bytes from `offset`. Without `no_increment` this is
+    /// `read_data`. With it each byte is read by its own single byte
+    /// `ReadBlock` of `offset`, bypassing the read cache, for registers or
+    /// FIFOs behind the SPI interface which aren't linear memory.
+    pub fn read_raw(&self, offset: u32, length: u32, no_increment: bool) -> Result<Vec<u8>> {
+        if !no_increment {
+            return self.read_data(offset, length);
+        }
+
+        ensure_address_range(offset, 1)?;
+
+        let mut data = Vec::with_capacity(length as _);
+        for _ in 0..length {
+            data.extend_from_slice(&self.read_block(offset, 1, None)?);
+        }
+
+        Ok(data)
+    }
+
+    fn read_data_cached(
+        &self,
+        offset: u32,
+        length: u32,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let block_size = self.layout.buf_size;
+        let end = offset + length;
+
+        let start_time = Instant::now();
+        let mut data = Vec::with_capacity(length as _);
+        let mut block = offset - offset % block_size;
+
+        while block < end {
+            if !data.is_empty() {
+                self.config.waiter.yield_now();
+            }
+
+            let values = match self
+                .ensure_total_time(start_time, data.len())
+                .and_then(|_| self.read_cached_block(block, timeout))
+            {
+                Ok(values) => values,
+                Err(err) => {
+                    let offset = cmp::max(block, offset);
+                    let elapsed = start_time.elapsed();
+                    return Err(PartialRead {
+                        data,
+                        offset,
+                        elapsed,
+                    }
+                    .into_error(Box::new(err)));
+                }
+            };
+
+            let start = offset.saturating_sub(block) as usize;
+            let stop = cmp::min(end - block, block_size) as usize;
+            data.extend_from_slice(&values[start..stop]);
+
+            block = match block.checked_add(block_size) {
+                Some(block) => block,
+                None => break,
+            };
+        }
+
+        Ok(data)
+    }
+
+    fn read_cached_block(&self, block: u32, timeout: Option<Duration>) -> Result<Vec<u8>> {
+        let mut cache = self.read_cache.borrow_mut();
+
+        if let Some(index) = cache.iter().position(|(offset, _)| *offset == block) {
+            let entry = cache.remove(index);
+            let values = entry.1.clone();
+            cache.push(entry);
+            return Ok(values);
+        }
+
+        let values = self.read_block(block, self.layout.buf_size, timeout)?;
+
+        if cache.len() >= self.config.read_cache_blocks {
+            cache.remove(0);
+        }
+        cache.push((block, values.clone()));
+
+        Ok(values)
+    }
+
+    fn invalidate_read_cache(&self, offset: u32, length: u64) {
+        let block_size = u64::from(self.layout.buf_size);
+        let start = u64::from(offset);
+        let end = start + length;
+
+        self.read_cache.borrow_mut().retain(|(block, _)| {
+            let block = u64::from(*block);
+            block + block_size <= start || block >= end
+        });
+    }
+
+    fn read_block(&self, offset: u32, length: u32, timeout: Option<Duration>) -> Result<Vec<u8>> {
+        let command = Command::ReadBlock { offset, length };
+        match self.send_command(command, timeout)? {
+            Response::Ok => {}
+            response => BadResponse { response }.fail()?,
+        }
+
+        let values = self.dss_read_datas(self.layout.buf_start, length)?;
+        StatsCounters::add(&self.stats.bytes_read, values.len() as _);
+
+        Ok(values)
+    }
+
+    pub fn write_data(&self, offset: u32, values: &[u8]) -> Result<()> {
+        self.write_data_timeout(offset, values, None)
+    }
+
+    /// Like `write_data`, but waits up to `timeout` for each block instead of
+    /// the default command timeout when given.
+    pub fn write_data_timeout(
+        &self,
+        offset: u32,
+        values: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.write_blocks(offset, values, timeout, None)
+    }
+
+    /// Like `write_data`, but calls `on_sector` with the base offset of each
+    /// sector once the write is done with it, for progress coarser than
+    /// blocks. Sectors are `SECTOR_SIZE`, like all sectors of the supported
+    /// flashes, see `Xflash::sector_map`. Nothing is called for an empty
+    /// write.
+    pub fn write_data_sectors(
+        &self,
+        offset: u32,
+        values: &[u8],
+        on_sector: &mut dyn FnMut(u32),
+    ) -> Result<()> {
+        self.write_blocks(offset, values, None, Some(on_sector))
+    }
+
+    fn write_blocks(
+        &self,
+        offset: u32,
+        values: &[u8],
+        timeout: Option<Duration>,
+        mut on_sector: Option<&mut dyn FnMut(u32)>,
+    ) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        ensure_address_range(offset, values.len())?;
+
+        self.invalidate_read_cache(offset, values.len() as _);
+
+        let end = u64::from(offset) + values.len() as u64;
+        let mut next_sector = u64::from(offset - offset % SECTOR_SIZE);
+
+        let start_time = Instant::now();
+        let mut offset = offset;
+
+        for (i, chunk) in values.chunks(self.layout.buf_size as _).enumerate() {
+            if i > 0 {
+                self.config.waiter.yield_now();
+            }
+
+            if i > 0 && self.config.write_pacing > Duration::from_secs(0) {
+                self.config.waiter.wait(self.config.write_pacing);
+            }
+
+            self.ensure_total_time(start_time, i * self.layout.buf_size as usize)?;
+
+            if self.config.require_erased_before_write {
+                let current = self.read_block(offset, chunk.len() as _, timeout)?;
+                if let Some(dirty) = current.iter().position(|&b| b != 0xFF) {
+                    return WriteToNonBlank {
+                        offset: offset + dirty as u32,
+                    }
+                    .fail();
+                }
+            }
+
+            self.dss_write_datas(self.layout.buf_start, chunk)?;
+
+            let command = Command::WriteBlock {
+                offset,
+                length: chunk.len() as _,
+            };
+            match self.send_command(command, timeout) {
+                Ok(Response::Ok) => {}
+                Ok(response) => BadResponse { response }.fail()?,
+                // Report how far the write got and how fast, to tell a stall
+                // apart from a degraded link
+                Err(err @ Error::ResponseTimeout { .. }) => {
+                    return Err(WriteStalled {
+                        offset,
+                        bytes_done: i * self.layout.buf_size as usize,
+                        elapsed: start_time.elapsed(),
+                    }
+                    .into_error(Box::new(err)))
+                }
+                Err(err) => return Err(err),
+            }
+
+            StatsCounters::add(&self.stats.bytes_written, chunk.len() as _);
+
+            // A sector is done once the write passed its end, or the write
+            // ended inside it
+            let written = u64::from(offset) + chunk.len() as u64;
+            if let Some(on_sector) = on_sector.as_mut() {
+                while next_sector < written
+                    && (next_sector + u64::from(SECTOR_SIZE) <= written || written == end)
+                {
+                    on_sector(next_sector as u32);
+                    next_sector += u64::from(SECTOR_SIZE);
+                }
+            }
+
+            offset += chunk.len() as u32;
+        }
+
+        Ok(())
+    }
+
+    /// Like `write_data`, but extends the write to whole flash pages by
+    /// reading the bytes before `offset` and after the end in the first and
+    /// last page, and writing them back unchanged. Every page is then
+    /// programmed in one go. Programming bytes with the value they already
+    /// have leaves them intact, so no erase is needed for the padding.
+    pub fn write_aligned(&self, offset: u32, values: &[u8]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        ensure_address_range(offset, values.len())?;
+
+        let end = offset + values.len() as u32;
+        let head_offset = offset - offset % PAGE_SIZE;
+        let tail_length = (PAGE_SIZE - end % PAGE_SIZE) % PAGE_SIZE;
+
+        let head = self.read_data(head_offset, offset - head_offset)?;
+        let tail = self.read_data(end, tail_length)?;
+
+        let aligned: Vec<u8> = head
+            .into_iter()
+            .chain(values.iter().copied())
+            .chain(tail.into_iter())
+            .collect();
+
+        self.write_data(head_offset, &aligned)
+    }
+
+    /// Blocks until the flash has committed all writes. The firmware doesn't
+    /// wait for the last page program of a `WriteBlock` to finish, but does
+    /// wait for the flash to be ready before reading, so this reads a single
+    /// byte. Flashes without write buffering are ready by then anyway.
+    pub fn flush(&self) -> Result<()> {
+        self.read_block(0, 1, None)?;
+        Ok(())
+    }
+
+    /// Writes `word` repeatedly over `length` bytes from `offset`, without
+    /// erasing first.
+    pub fn fill_word(
+        &self,
+        offset: u32,
+        length: u32,
+        word: u32,
+        endianness: Endianness,
+    ) -> Result<()> {
+        ensure!(length % 4 == 0, UnalignedLength { length });
+
+        self.write_data(offset, &fill_pattern(word, endianness, length))
+    }
+
+    /// Erases according to `erase` and writes `data` at `offset`. With
+    /// `EraseMode::Range` any data outside the written range in the touched
+    /// sectors is lost. Returns the number of verification retries needed.
+    pub fn program(&self, offset: u32, data: &[u8], erase: EraseMode, verify: bool) -> Result<u8> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        ensure_address_range(offset, data.len())?;
+
+        if self.config.presence_check {
+            ensure!(self.is_present()?, NoFlashDetected {});
+        }
+
+        let length = data.len() as u32;
+
+        match erase {
+            EraseMode::None => {}
+            EraseMode::Range => {
+                let first_address = offset - offset % SECTOR_SIZE;
+                let last_address = offset + length;
+                let last_length = (SECTOR_SIZE - last_address % SECTOR_SIZE) % SECTOR_SIZE;
+
+                // The firmware only erases every touched sector when the range
+                // is sector aligned at both ends
+                self.sector_erase(first_address, last_address - first_address + last_length)?;
+            }
+            EraseMode::Mass => self.mass_erase()?,
+        }
+
+        self.write_data(offset, data)?;
+
+        if !verify {
+            self.flush()?;
+            return Ok(0);
+        }
+
+        let mut retries = 0;
+
+        loop {
+            let read_back = self.read_data(offset, length)?;
+            let failed_sectors = mismatched_sectors(offset, data, &read_back);
 
-#[derive(Debug)]
-enum Command {
-    GetXflashInfo,
-    SectorErase { offset: u32, length: u32 },
-    MassErase,
-    ReadBlock { offset: u32, length: u32 },
-    WriteBlock { offset: u32, length: u32 },
-}
+            if failed_sectors.is_empty() {
+                return Ok(retries);
+            }
 
-impl Command {
-    fn to_bytes(&self) -> [u32; 4] {
-        use Command::*;
+            ensure!(
+                erase != EraseMode::None && retries < self.config.verify_retries,
+                VerificationFailed {}
+            );
+            retries += 1;
+            StatsCounters::add(&self.stats.retries, 1);
 
-        match self {
-            GetXflashInfo => [0xC0_u32.to_le(), 0, 0, 0],
-            SectorErase { offset, length } => [0xC1_u32.to_le(), offset.to_le(), length.to_le(), 0],
-            MassErase => [0xC2_u32.to_le(), 0, 0, 0],
-            ReadBlock { offset, length } => [0xC3_u32.to_le(), offset.to_le(), length.to_le(), 0],
-            WriteBlock { offset, length } => [0xC4_u32.to_le(), offset.to_le(), length.to_le(), 0],
+            for sector in failed_sectors {
+                let start = cmp::max(sector, offset);
+                let end = cmp::min(sector.saturating_add(SECTOR_SIZE), offset + length);
+                let values = &data[(start - offset) as usize..(end - offset) as usize];
+
+                self.sector_erase(sector, SECTOR_SIZE)?;
+                self.write_data(start, values)?;
+            }
         }
     }
-}
 
-#[derive(Debug)]
-pub enum Response {
-    Ok,
-    XflashInfo(Xflash),
-}
+    /// Writes each `(offset, data)` segment, leaving the gaps between them as
+    /// they are. Only the touched sectors are erased, and only the bytes in
+    /// them that no segment covers are read back and rewritten. Where
+    /// segments overlap the later one wins.
+    pub fn write_sparse<D: AsRef<[u8]>>(&self, segments: &[(u32, D)]) -> Result<()> {
+        let mut sectors = BTreeSet::new();
+
+        for (offset, data) in segments {
+            let data = data.as_ref();
+            if data.is_empty() {
+                continue;
+            }
+            ensure_address_range(*offset, data.len())?;
 
-impl Response {
-    fn from_bytes(bytes: &[u32; 4]) -> Result<Self> {
-        const OK_VAL: u32 = 0xD0_u32.to_le();
-        const XFLASHINFO_VAL: u32 = 0xD1_u32.to_le();
+            let first_sector = offset / SECTOR_SIZE;
+            let last_sector = (offset + (data.len() as u32 - 1)) / SECTOR_SIZE;
+            sectors.extend(first_sector..=last_sector);
+        }
 
-        let rsp = match bytes {
-            [OK_VAL, 0, 0, 0] => Response::Ok,
-            [XFLASHINFO_VAL, mid, did, 0] => Response::XflashInfo(Xflash::from_id(*mid, *did)),
-            _ => InvalidResponse { bytes: *bytes }.fail()?,
-        };
-        Ok(rsp)
-    }
-}
+        // Adjacent sectors are merged into runs of (first sector, count), so
+        // that each sector is erased exactly once
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for sector in sectors {
+            match runs.last_mut() {
+                Some((first, count)) if *first + *count == sector => *count += 1,
+                _ => runs.push((sector, 1)),
+            }
+        }
 
-const SRAM_START: u32 = 0x2000_0000;
-const STACK_ADDR: u32 = SRAM_START;
-const RESET_ISR: u32 = SRAM_START + 0x04;
+        for (first_sector, count) in runs {
+            let start = first_sector * SECTOR_SIZE;
+            let length = count * SECTOR_SIZE;
+
+            // The parts of the run each segment covers, relative to the start
+            let overlaps: Vec<(usize, usize, &[u8])> = segments
+                .iter()
+                .filter_map(|(offset, data)| {
+                    let data = data.as_ref();
+                    let overlap_start = cmp::max(*offset, start);
+                    let overlap_end = cmp::min(*offset + data.len() as u32, start + length);
+                    if overlap_start >= overlap_end {
+                        return None;
+                    }
+                    Some((
+                        (overlap_start - start) as usize,
+                        (overlap_end - start) as usize,
+                        &data[(overlap_start - offset) as usize..(overlap_end - offset) as usize],
+                    ))
+                })
+                .collect();
+
+            // Read back only the gaps between the covered parts
+            let mut covered: Vec<(usize, usize)> =
+                overlaps.iter().map(|&(from, to, _)| (from, to)).collect();
+            covered.sort();
+
+            let mut image = vec![0xFF; length as usize];
+            let mut gap_start = 0;
+            for (from, to) in covered
+                .into_iter()
+                .chain(Some((length as usize, length as usize)))
+            {
+                if from > gap_start {
+                    let gap =
+                        self.read_data(start + gap_start as u32, (from - gap_start) as u32)?;
+                    image[gap_start..from].copy_from_slice(&gap);
+                }
+                gap_start = cmp::max(gap_start, to);
+            }
 
-const CONF_START: u32 = 0x2000_3000;
+            for (from, to, data) in overlaps {
+                image[from..to].copy_from_slice(data);
+            }
 
-const CONF_VALID: u32 = CONF_START;
-const CONF_SPI_MISO: u32 = CONF_START + 0x04;
-const CONF_SPI_MOSI: u32 = CONF_START + 0x08;
-const CONF_SPI_CLK: u32 = CONF_START + 0x0C;
-const CONF_SPI_CSN: u32 = CONF_START + 0x10;
+            self.sector_erase(start, length)?;
+            self.write_data(start, &image)?;
+        }
 
-const DOORBELL_START: u32 = 0x2000_3100;
+        Ok(())
+    }
 
-const DOORBELL_CMD_KIND: u32 = DOORBELL_START;
-const DOORBELL_CMD_ARG0: u32 = DOORBELL_START + 0x04;
-const DOORBELL_CMD_ARG1: u32 = DOORBELL_START + 0x08;
-const DOORBELL_CMD_ARG2: u32 = DOORBELL_START + 0x0C;
+    /// Writes everything `reader` yields from `offset`, one firmware buffer
+    /// at a time, so the whole image is never held in memory. Like
+    /// `write_data` the range is written in-place without erasing. Returns
+    /// the number of bytes written.
+    pub fn write_from_reader(&self, offset: u32, mut reader: impl io::Read) -> Result<u64> {
+        let mut buf = vec![0u8; self.layout.buf_size as usize];
+        let mut written: u64 = 0;
 
-const DOORBELL_RSP_KIND: u32 = DOORBELL_START + 0x10;
-const DOORBELL_RSP_VAL0: u32 = DOORBELL_START + 0x14;
-const DOORBELL_RSP_VAL1: u32 = DOORBELL_START + 0x18;
-const DOORBELL_RSP_VAL2: u32 = DOORBELL_START + 0x1C;
+        loop {
+            let filled = read_full(&mut reader, &mut buf)?;
 
-const BUF_START: u32 = 0x2000_4000;
-pub const BUF_SIZE: u32 = 0x1000;
+            if filled == 0 {
+                break;
+            }
 
-pub struct Firmware<'a> {
-    memory: Memory<'a>,
-    binary: TempPath,
-}
+            ensure_address_range(offset, written as usize + filled)?;
+            self.write_data(offset + written as u32, &buf[..filled])?;
+            written += filled as u64;
 
-impl<'a> Firmware<'a> {
-    pub fn new(memory: Memory<'a>, device: Device) -> Result<Firmware<'a>> {
-        let binary = Firmware::create_firmware_binary(device)?;
+            if filled < buf.len() {
+                break;
+            }
+        }
 
-        Ok(Self { memory, binary })
+        Ok(written)
     }
 
-    pub fn inject(&self, spi_pins: Option<SpiPins>) -> Result<()> {
-        let binary_path = self.binary.to_string_lossy().to_owned();
+    /// Compares the file at `path` against the flash from `offset`, one
+    /// firmware buffer at a time so neither is held in memory. Fails with
+    /// `ImageMismatch` at the first differing byte, and with `BeyondCapacity`
+    /// if a known flash ends before the file does.
+    pub fn verify_against_file(&self, offset: u32, path: &Path) -> Result<()> {
+        let file = fs::File::open(path).context(ImageOpen { path })?;
+        let length = file.metadata().context(ImageOpen { path })?.len();
+
+        ensure_address_range(offset, length as _)?;
+        if let Some(capacity) = self.get_xflash_info()?.capacity() {
+            ensure!(
+                u64::from(offset) + length <= u64::from(capacity),
+                BeyondCapacity {
+                    offset,
+                    length: length as u32,
+                    capacity
+                }
+            );
+        }
 
-        self.dss_load_raw(&binary_path)?;
+        let mut reader = io::BufReader::new(file);
+        let mut buf = vec![0u8; self.layout.buf_size as usize];
+        let mut block_offset = offset;
+
+        loop {
+            let filled = read_full(&mut reader, &mut buf)?;
+            if filled == 0 {
+                return Ok(());
+            }
 
-        if let Some(spi_pins) = spi_pins {
-            self.dss_write_data(CONF_VALID, 1)?;
-            self.dss_write_data(CONF_SPI_MISO, spi_pins[SpiPin::Miso] as _)?;
-            self.dss_write_data(CONF_SPI_MOSI, spi_pins[SpiPin::Mosi] as _)?;
-            self.dss_write_data(CONF_SPI_CLK, spi_pins[SpiPin::Clk] as _)?;
-            self.dss_write_data(CONF_SPI_CSN, spi_pins[SpiPin::Csn] as _)?;
+            let data = self.read_data(block_offset, filled as _)?;
+            if let Some(i) = data.iter().zip(&buf[..filled]).position(|(a, b)| a != b) {
+                return ImageMismatch {
+                    offset: block_offset + i as u32,
+                }
+                .fail();
+            }
+
+            block_offset += filled as u32;
         }
+    }
 
-        let stack_addr = self.dss_read_data(STACK_ADDR)?;
-        let reset_isr = self.dss_read_data(RESET_ISR)?;
+    /// Decompresses the gzip file at `path` while writing it from `offset`,
+    /// see `write_from_reader`. Returns the number of decompressed bytes
+    /// written.
+    #[cfg(feature = "gzip")]
+    pub fn write_from_gzip(&self, offset: u32, path: &Path) -> Result<u64> {
+        let file = fs::File::open(path).context(ImageOpen { path })?;
 
-        self.dss_write_register(Register::MSP, stack_addr)?;
-        self.dss_write_register(Register::PC, reset_isr)?;
-        self.dss_write_register(Register::LR, 0xFFFF_FFFF)?;
+        self.write_from_reader(offset, GzDecoder::new(io::BufReader::new(file)))
+    }
 
-        Ok(())
+    /// Size in bytes of the detected flash, if it's a known one
+    pub fn capacity(&self) -> Result<u32> {
+        let xflash = self.get_xflash_info()?;
+        xflash.capacity().context(UnknownCapacity { xflash })
     }
 
-    pub fn get_xflash_info(&self) -> Result<Xflash> {
-        let command = Command::GetXflashInfo;
-        match self.send_command(command, None)? {
-            Response::XflashInfo(xflash) => Ok(xflash),
-            response => BadResponse { response }.fail(),
+    /// SHA-256 of the whole flash, from offset 0 up to the capacity of the
+    /// detected flash. Only known flashes have a capacity to hash up to.
+    #[cfg(feature = "sha2")]
+    pub fn fingerprint(&self) -> Result<[u8; 32]> {
+        let capacity = self.capacity()?;
+
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+
+        while offset < capacity {
+            let length = cmp::min(capacity - offset, self.layout.buf_size);
+            hasher.update(&self.read_data(offset, length)?);
+            offset += length;
         }
-    }
 
-    pub fn sector_erase(&self, offset: u32, length: u32) -> Result<()> {
-        // Plus one for margin, as the write range can touch two sectors: one at
-        // the beginnning and one at the end
-        let num_sectors = length / BUF_SIZE + 1;
-        let timeout = num_sectors * Duration::from_millis(500);
+        Ok(hasher.finalize().into())
+    }
 
-        let command = Command::SectorErase { offset, length };
-        match self.send_command(command, Some(timeout))? {
-            Response::Ok => Ok(()),
-            response => BadResponse { response }.fail(),
+    /// SHA-256 of each `chunk` bytes from `offset`, hashed block by block as
+    /// they are read so only one block is held at a time. The last chunk may
+    /// be shorter. For deduplication `chunk` is the store's block size, and
+    /// should be a multiple of `SECTOR_SIZE` so that a changed sector only
+    /// changes the hash of the one chunk it's in.
+    #[cfg(feature = "sha2")]
+    pub fn read_chunked_hashes(
+        &self,
+        offset: u32,
+        length: u32,
+        chunk: u32,
+    ) -> Result<Vec<[u8; 32]>> {
+        ensure!(chunk > 0, ZeroChunkSize {});
+        ensure_address_range(offset, length as _)?;
+
+        let mut hashes = Vec::with_capacity(div_ceil(length, chunk) as _);
+        let mut hasher = Sha256::new();
+        let mut hashed = 0;
+        let mut done = 0;
+
+        while done < length {
+            let ilength = cmp::min(
+                cmp::min(chunk - hashed, length - done),
+                self.layout.buf_size,
+            );
+            hasher.update(&self.read_data(offset + done, ilength)?);
+            hashed += ilength;
+            done += ilength;
+
+            if hashed == chunk || done == length {
+                hashes.push(hasher.finalize_reset().into());
+                hashed = 0;
+            }
         }
+
+        Ok(hashes)
     }
 
-    pub fn mass_erase(&self) -> Result<()> {
-        const TIMEOUT: Duration = Duration::from_secs(240);
+    /// Reads and parses the OAD image header at offset 0, see `FlashHeader`
+    /// for the layout. Returns `None` if there's no known image ID.
+    pub fn read_header(&self) -> Result<Option<FlashHeader>> {
+        let bytes = self.read_data(0, FlashHeader::SIZE)?;
+        Ok(FlashHeader::parse(&bytes))
+    }
 
-        let command = Command::MassErase;
-        match self.send_command(command, Some(TIMEOUT))? {
-            Response::Ok => {}
-            response => BadResponse { response }.fail()?,
+    /// Erases, programs and verifies the sector containing `offset` up to
+    /// `cycles` times, stopping at the first cycle which fails to verify.
+    /// The pattern alternates between 0x55 and 0xAA so every bit is
+    /// programmed every other cycle. This destroys the data in the sector,
+    /// which is left erased if all cycles pass. Zero cycles leaves the
+    /// sector untouched.
+    pub fn endurance_test(
+        &self,
+        offset: u32,
+        cycles: u32,
+        progress: &mut dyn ProgressSink,
+    ) -> Result<EnduranceReport> {
+        if cycles == 0 {
+            return Ok(EnduranceReport {
+                cycles_passed: 0,
+                failure: None,
+            });
         }
 
-        Ok(())
-    }
+        let sector = offset - offset % SECTOR_SIZE;
+        ensure_address_range(sector, SECTOR_SIZE as _)?;
 
-    pub fn read_data(&self, offset: u32, length: u32) -> Result<Vec<u8>> {
-        if length == 0 {
-            return Ok(Vec::new());
+        let first_mismatch = |data: &[u8], expected: u8| {
+            data.iter()
+                .position(|&b| b != expected)
+                .map(|i| sector + i as u32)
+        };
+
+        for cycle in 0..cycles {
+            progress.percent((u64::from(cycle) * 100 / u64::from(cycles)) as u8);
+
+            self.sector_erase(sector, SECTOR_SIZE)?;
+            if let Some(offset) = first_mismatch(&self.read_data(sector, SECTOR_SIZE)?, 0xFF) {
+                return Ok(EnduranceReport {
+                    cycles_passed: cycle,
+                    failure: Some(EnduranceFailure::Erase { cycle, offset }),
+                });
+            }
+
+            let pattern = if cycle % 2 == 0 { 0x55 } else { 0xAA };
+            self.write_data(sector, &[pattern; SECTOR_SIZE as usize])?;
+            if let Some(offset) = first_mismatch(&self.read_data(sector, SECTOR_SIZE)?, pattern) {
+                return Ok(EnduranceReport {
+                    cycles_passed: cycle,
+                    failure: Some(EnduranceFailure::Program { cycle, offset }),
+                });
+            }
         }
 
-        let mut data = Vec::with_capacity(length as _);
+        self.sector_erase(sector, SECTOR_SIZE)?;
+        progress.percent(100);
 
-        let mut offset = offset;
-        let mut length = length;
+        Ok(EnduranceReport {
+            cycles_passed: cycles,
+            failure: None,
+        })
+    }
 
-        // let mut zero_vec = Vec::with_capacity(BUF_SIZE as _);
-        // zero_vec.resize_with(BUF_SIZE as _, || 0);
+    /// Like `read_data`, but also returns whether each flash page in the range
+    /// reads as all 0xFF, i.e. is likely still erased. The first and last
+    /// entries cover only the part of their page inside the range. There is
+    /// no CRC command in the firmware, so this checks the read data.
+    pub fn read_with_blank_map(&self, offset: u32, length: u32) -> Result<(Vec<u8>, Vec<bool>)> {
+        let data = self.read_data(offset, length)?;
+
+        let mut blank = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            let address = u64::from(offset) + start as u64;
+            let page_end = address - address % u64::from(PAGE_SIZE) + u64::from(PAGE_SIZE);
+            let end = cmp::min((page_end - u64::from(offset)) as usize, data.len());
+            blank.push(data[start..end].iter().all(|&b| b == 0xFF));
+            start = end;
+        }
 
-        while length > 0 {
-            let ilength = std::cmp::min(length, BUF_SIZE as _);
+        Ok((data, blank))
+    }
 
-            // self.dss_write_datas(BUF_START, &zero_vec)?;
+    /// Reads `length` bytes from `offset` into a snapshot addressed by flash
+    /// offset
+    pub fn snapshot(&self, offset: u32, length: u32) -> Result<FlashSnapshot> {
+        Ok(FlashSnapshot {
+            base: offset,
+            data: self.read_data(offset, length)?,
+        })
+    }
+
+    /// Reads `size_of::<T>()` bytes from `offset` and parses them as a `T`.
+    /// Byte order is up to the fields of `T`, e.g. `zerocopy::byteorder`
+    /// types for a fixed endianness.
+    #[cfg(feature = "zerocopy")]
+    pub fn read_as<T: FromBytes>(&self, offset: u32) -> Result<T> {
+        let data = self.read_data(offset, std::mem::size_of::<T>() as _)?;
 
-            let command = Command::ReadBlock {
+        Ok(T::read_from(data.as_slice()).expect("read_data returns the requested length"))
+    }
+
+    /// Reads `count` pages from page number `start_page`, the same as
+    /// `read_data` of `count * PAGE_SIZE` bytes from `start_page * PAGE_SIZE`
+    /// split into pages. Fails with `BeyondCapacity` if the pages go past the
+    /// end of the flash, so only known flashes can be read by page.
+    pub fn read_pages(&self, start_page: u32, count: u32) -> Result<Vec<[u8; PAGE_SIZE as usize]>> {
+        let capacity = self.capacity()?;
+
+        let (offset, length) =
+            page_range(start_page, count).context(PageOverflow { start_page, count })?;
+        ensure!(
+            u64::from(offset) + u64::from(length) <= u64::from(capacity),
+            BeyondCapacity {
                 offset,
-                length: ilength,
-            };
-            match self.send_command(command, None)? {
-                Response::Ok => {}
-                response => BadResponse { response }.fail()?,
+                length,
+                capacity
             }
+        );
 
-            let values = self.dss_read_datas(BUF_START, ilength)?;
-            data.extend_from_slice(&values);
+        let data = self.read_data(offset, length)?;
 
-            offset += ilength;
-            length -= ilength;
-        }
+        let pages = data
+            .chunks_exact(PAGE_SIZE as usize)
+            .map(|chunk| {
+                let mut page = [0; PAGE_SIZE as usize];
+                page.copy_from_slice(chunk);
+                page
+            })
+            .collect();
 
-        Ok(data)
+        Ok(pages)
     }
 
-    pub fn write_data(&self, offset: u32, values: &[u8]) -> Result<()> {
-        if values.is_empty() {
-            return Ok(());
-        }
+    /// Changes the bytes at `offset` to `data` and retains the rest of the
+    /// enclosing sectors, by reading them, erasing them and writing them back
+    /// with `data` applied. This is slower than a plain write, since whole
+    /// sectors go both ways, but safe for in-place edits where bits have to be
+    /// set back to 1.
+    pub fn patch(&self, offset: u32, data: &[u8]) -> Result<()> {
+        self.write_sparse(&[(offset, data)])?;
 
-        let mut offset = offset;
+        let read_back = self.read_data(offset, data.len() as _)?;
+        ensure!(data == read_back.as_slice(), VerificationFailed {});
 
-        for chunk in values.chunks(BUF_SIZE as _) {
-            self.dss_write_datas(BUF_START, chunk)?;
+        Ok(())
+    }
 
-            let command = Command::WriteBlock {
-                offset,
-                length: chunk.len() as _,
-            };
-            match self.send_command(command, None)? {
-                Response::Ok => {}
-                response => BadResponse { response }.fail()?,
+    fn ensure_total_time(&self, start_time: Instant, bytes_done: usize) -> Result<()> {
+        if let Some(total_timeout) = self.config.total_timeout {
+            ensure!(
+                start_time.elapsed() < total_timeout,
+                TotalTimeout { bytes_done }
+            );
+        }
+        Ok(())
+    }
+
+    /// Passes `result` through, counting it in the stats if it's an error
+    fn counted<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(err) = &result {
+            if let Error::FirmwareTimeout { .. } | Error::ResponseTimeout { .. } = err {
+                StatsCounters::add(&self.stats.timeouts, 1);
             }
+            StatsCounters::add(&self.stats.errors, 1);
+        }
+        result
+    }
 
-            offset += chunk.len() as u32;
+    fn post_command(&self, command: Command) -> Result<()> {
+        StatsCounters::add(&self.stats.commands, 1);
+        self.in_flight.set(true);
+
+        for &(offset, word) in &doorbell_writes(command) {
+            self.dss_write_data(self.doorbell(offset), word)?;
         }
 
         Ok(())
     }
 
+    /// Reads the response words and clears the response kind, letting the
+    /// firmware wait for the next command
+    fn take_response(&self) -> Result<[u32; 4]> {
+        // The response words are consecutive, so read them in one go
+        let words = self.dss_read_words(self.doorbell(DOORBELL_RSP_KIND), DOORBELL_RSP_WORDS)?;
+        ensure!(words.len() == DOORBELL_RSP_WORDS as usize, NoResponse {});
+        let bytes: [u32; 4] = [words[0], words[1], words[2], words[3]];
+
+        self.dss_write_data(self.doorbell(DOORBELL_RSP_KIND), 0)?;
+        self.in_flight.set(false);
+
+        Ok(bytes)
+    }
+
     fn send_command(&self, command: Command, timeout: Option<Duration>) -> Result<Response> {
-        let bytes = command.to_bytes();
+        let start_time = Instant::now();
+        let response = self.exchange_command(command, timeout);
+
+        let (count, total) = self.command_time.get();
+        self.command_time
+            .set((count + 1, total + start_time.elapsed()));
 
-        self.dss_write_data(DOORBELL_CMD_ARG2, bytes[3])?;
-        self.dss_write_data(DOORBELL_CMD_ARG1, bytes[2])?;
-        self.dss_write_data(DOORBELL_CMD_ARG0, bytes[1])?;
-        self.dss_write_data(DOORBELL_CMD_KIND, bytes[0])?;
+        self.counted(response)
+    }
+
+    fn exchange_command(&self, command: Command, timeout: Option<Duration>) -> Result<Response> {
+        self.post_command(command)?;
 
-        const DWELL_TIME: Duration = Duration::from_millis(100);
         const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 
         let timeout = timeout.unwrap_or(DEFAULT_TIMEOUT);
 
-        let sys_time = SystemTime::now();
+        let mut polls = 0;
+        let waited = self.wait_for_response(command, timeout, &mut polls);
+        self.stats.add_polls(polls);
+        waited?;
 
-        while self.dss_read_data(DOORBELL_CMD_KIND)? != 0
-            && sys_time.elapsed().unwrap_or_default() < timeout
-        {
-            thread::sleep(DWELL_TIME);
-        }
+        let bytes = self.take_response()?;
 
-        if sys_time.elapsed().unwrap_or_default() >= timeout {
-            return FirmwareTimeout {}.fail();
-        }
+        Ok(Response::from_bytes(&bytes)?)
+    }
 
+    /// Polls the doorbell until the firmware has taken the command and posted
+    /// a response, counting each doorbell read in `polls`. Each step gets the
+    /// full `timeout`.
+    fn wait_for_response(
+        &self,
+        command: Command,
+        timeout: Duration,
+        polls: &mut u32,
+    ) -> Result<()> {
         let sys_time = SystemTime::now();
 
-        while self.dss_read_data(DOORBELL_RSP_KIND)? == 0
-            && sys_time.elapsed().unwrap_or_default() < timeout
-        {
-            thread::sleep(DWELL_TIME);
+        loop {
+            *polls += 1;
+            if self.dss_read_data(self.doorbell(DOORBELL_CMD_KIND))? == 0 {
+                break;
+            }
+            if sys_time.elapsed().unwrap_or_default() >= timeout {
+                return ResponseTimeout {
+                    command,
+                    command_acked: false,
+                }
+                .fail();
+            }
+            self.config.waiter.wait(DWELL_TIME);
         }
 
-        if sys_time.elapsed().unwrap_or_default() >= timeout {
-            return FirmwareTimeout {}.fail();
+        let sys_time = SystemTime::now();
+
+        loop {
+            *polls += 1;
+            if self.dss_read_data(self.doorbell(DOORBELL_RSP_KIND))? != 0 {
+                break;
+            }
+            if sys_time.elapsed().unwrap_or_default() >= timeout {
+                return ResponseTimeout {
+                    command,
+                    command_acked: true,
+                }
+                .fail();
+            }
+            self.config.waiter.wait(DWELL_TIME);
         }
 
-        let bytes: [u32; 4] = [
-            self.dss_read_data(DOORBELL_RSP_KIND)?,
-            self.dss_read_data(DOORBELL_RSP_VAL0)?,
-            self.dss_read_data(DOORBELL_RSP_VAL1)?,
-            self.dss_read_data(DOORBELL_RSP_VAL2)?,
-        ];
+        Ok(())
+    }
 
-        self.dss_write_data(DOORBELL_RSP_KIND, 0)?;
+    fn conf(&self, offset: u32) -> u32 {
+        self.layout.conf_start + offset
+    }
 
-        Ok(Response::from_bytes(&bytes)?)
+    fn doorbell(&self, offset: u32) -> u32 {
+        self.layout.doorbell_start + offset
     }
 
     fn dss_write_data(&self, address: u32, value: u32) -> Result<()> {
@@ -320,18 +2357,63 @@ impl<'a> Firmware<'a> {
         Ok(data as _)
     }
 
+    /// Reads the word-aligned part of the range at width 32, which DSS
+    /// transfers with a quarter of the accesses, and any tail at width 8. The
+    /// devices are all little-endian, so each word splits into its bytes in
+    /// memory order with `to_le_bytes`.
     fn dss_read_datas(&self, address: u32, size: u32) -> Result<Vec<u8>> {
+        let words = if address % 4 == 0 { size / 4 } else { 0 };
+
+        let mut values = Vec::with_capacity(size as usize);
+        if words > 0 {
+            for word in self.dss_read_words(address, words)? {
+                values.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        let tail = size - words * 4;
+        if tail > 0 {
+            let datas = self
+                .memory
+                .read_datas(0, (address + words * 4) as _, 8, tail as _, false as _)
+                .context(DssError {})?;
+            values.extend(datas.iter().map(|n| *n as u8));
+        }
+
+        Ok(values)
+    }
+
+    fn dss_read_words(&self, address: u32, count: u32) -> Result<Vec<u32>> {
         let datas = self
             .memory
-            .read_datas(0, address as _, 8, size as _, false as _)
+            .read_datas(0, address as _, 32, count as _, false as _)
             .context(DssError {})?;
         let values = datas.iter().map(|n| *n as _).collect();
         Ok(values)
     }
 
+    /// Reads a vector table word at `vector_read_width`, assembling it from
+    /// little-endian parts at the narrower widths
+    fn dss_read_vector(&self, address: u32) -> Result<u32> {
+        let width = self.config.vector_read_width;
+        if width == 32 {
+            return self.dss_read_data(address);
+        }
+
+        let datas = self
+            .memory
+            .read_datas(0, address as _, width as _, (32 / width) as _, false as _)
+            .context(DssError {})?;
+        let value = datas
+            .iter()
+            .rev()
+            .fold(0u32, |value, &part| (value << width) | part as u32);
+        Ok(value)
+    }
+
     fn dss_load_raw(&self, file_name: &str) -> Result<()> {
         self.memory
-            .load_raw(0, SRAM_START as _, file_name, 32, false as _)
+            .load_raw(0, self.layout.sram_start as _, file_name, 32, false as _)
             .context(DssError {})?;
         Ok(())
     }
@@ -343,22 +2425,437 @@ impl<'a> Firmware<'a> {
         Ok(())
     }
 
-    fn create_firmware_binary(device: Device) -> Result<TempPath> {
+    /// Writes the firmware asset to a temp file for DSS to load, and returns
+    /// its path along with the first `LOAD_CHECK_WORDS` words of it
+    fn create_firmware_binary(
+        device: Device,
+        temp_dir: Option<&Path>,
+    ) -> Result<(TempPath, Vec<u32>)> {
         let asset = assets::get_firmware(device)
             .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Firmware asset not found"))
             .context(FirmwareAsset {})?;
 
+        let dir = temp_dir.map_or_else(env::temp_dir, Path::to_path_buf);
+
         let mut firmware = tempfile::Builder::new()
             .prefix("flash-rover.fw.")
             .suffix(".bin")
-            .tempfile()
-            .context(FirmwareAsset {})?;
-        firmware.write_all(&asset).context(FirmwareAsset {})?;
+            .tempfile_in(&dir)
+            .context(TempDirUnwritable { dir: dir.clone() })?;
+        firmware
+            .write_all(&asset)
+            .context(TempDirUnwritable { dir })?;
         let (file, path) = firmware.into_parts();
         // Drop file in order to ensure file is closed and written changes are
         // saved
         drop(file);
 
-        Ok(path)
+        let head = asset
+            .chunks_exact(4)
+            .take(LOAD_CHECK_WORDS as usize)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+
+        Ok((path, head))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_pattern_little_endian() {
+        assert_eq!(
+            fill_pattern(0x1122_3344, Endianness::Little, 8),
+            [0x44, 0x33, 0x22, 0x11, 0x44, 0x33, 0x22, 0x11]
+        );
+    }
+
+    #[test]
+    fn fill_pattern_big_endian() {
+        assert_eq!(
+            fill_pattern(0x1122_3344, Endianness::Big, 8),
+            [0x11, 0x22, 0x33, 0x44, 0x11, 0x22, 0x33, 0x44]
+        );
+    }
+
+    #[test]
+    fn fill_pattern_empty() {
+        assert!(fill_pattern(0xDEAD_BEEF, Endianness::Little, 0).is_empty());
+    }
+
+    #[test]
+    fn address_range_from_zero() {
+        assert!(ensure_address_range(0, 0).is_ok());
+        assert!(ensure_address_range(0, u32::MAX as usize).is_ok());
+    }
+
+    #[test]
+    fn address_range_up_to_last_address() {
+        assert!(ensure_address_range(u32::MAX - 1, 1).is_ok());
+        assert!(ensure_address_range(u32::MAX, 0).is_ok());
+    }
+
+    #[test]
+    fn address_range_overflow() {
+        assert!(matches!(
+            ensure_address_range(u32::MAX, 1),
+            Err(Error::AddressOverflow { .. })
+        ));
+        assert!(matches!(
+            ensure_address_range(1, u32::MAX as usize),
+            Err(Error::AddressOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn command_to_bytes() {
+        assert_eq!(Command::GetXflashInfo.to_bytes(), [0xC0, 0, 0, 0]);
+        assert_eq!(Command::MassErase.to_bytes(), [0xC2, 0, 0, 0]);
+        assert_eq!(
+            Command::SectorErase {
+                offset: 0x1000,
+                length: 0x2000
+            }
+            .to_bytes(),
+            [0xC1, 0x1000, 0x2000, 0]
+        );
+        assert_eq!(
+            Command::ReadBlock {
+                offset: 0x12_3456,
+                length: 0x100
+            }
+            .to_bytes(),
+            [0xC3, 0x12_3456, 0x100, 0]
+        );
+        assert_eq!(
+            Command::WriteBlock {
+                offset: 0xFFFF_FF00,
+                length: 4
+            }
+            .to_bytes(),
+            [0xC4, 0xFFFF_FF00, 4, 0]
+        );
+    }
+
+    #[test]
+    fn doorbell_writes_kind_last() {
+        let command = Command::WriteBlock {
+            offset: 0x2000,
+            length: 0x100,
+        };
+
+        assert_eq!(
+            doorbell_writes(command),
+            [
+                (DOORBELL_CMD_ARG2, 0),
+                (DOORBELL_CMD_ARG1, 0x100),
+                (DOORBELL_CMD_ARG0, 0x2000),
+                (DOORBELL_CMD_KIND, 0xC4),
+            ]
+        );
+    }
+
+    #[test]
+    fn doorbell_writes_every_command_word() {
+        for &command in &[
+            Command::GetXflashInfo,
+            Command::MassErase,
+            Command::SectorErase {
+                offset: 0x1000,
+                length: 0x1000,
+            },
+            Command::ReadBlock {
+                offset: 0x10,
+                length: 4,
+            },
+        ] {
+            let writes = doorbell_writes(command);
+            let mut offsets: Vec<u32> = writes.iter().map(|&(offset, _)| offset).collect();
+            offsets.sort();
+
+            assert_eq!(
+                offsets,
+                [
+                    DOORBELL_CMD_KIND,
+                    DOORBELL_CMD_ARG0,
+                    DOORBELL_CMD_ARG1,
+                    DOORBELL_CMD_ARG2
+                ]
+            );
+            assert_eq!(writes[3], (DOORBELL_CMD_KIND, command.opcode()));
+        }
+    }
+
+    #[test]
+    fn response_read_covers_the_response_words() {
+        let addresses: Vec<u32> = (0..DOORBELL_RSP_WORDS)
+            .map(|word| DOORBELL_RSP_KIND + 4 * word)
+            .collect();
+
+        assert_eq!(
+            addresses,
+            [
+                DOORBELL_RSP_KIND,
+                DOORBELL_RSP_VAL0,
+                DOORBELL_RSP_VAL1,
+                DOORBELL_RSP_VAL2
+            ]
+        );
+        // The firmware packs the response right after the four command words
+        assert_eq!(DOORBELL_RSP_KIND, DOORBELL_CMD_ARG2 + 4);
+    }
+
+    #[test]
+    fn response_ok() {
+        assert!(matches!(
+            Response::from_bytes(&[0xD0, 0, 0, 0]),
+            Ok(Response::Ok)
+        ));
+    }
+
+    #[test]
+    fn response_xflash_info() {
+        match Response::from_bytes(&[0xD1, 0xC2, 0x17, 0]) {
+            Ok(Response::XflashInfo(xflash)) => assert_eq!(xflash, Xflash::from_id(0xC2, 0x17)),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn response_invalid() {
+        for bytes in &[
+            [0x80, 0, 0, 0],
+            [0xD0, 1, 0, 0],
+            [0xD1, 0xC2, 0x17, 1],
+            [0, 0, 0, 0],
+        ] {
+            assert!(matches!(
+                Response::from_bytes(bytes),
+                Err(Error::InvalidResponse { .. })
+            ));
+        }
+    }
+
+    fn invalid_response() -> Result<Xflash> {
+        InvalidResponse {
+            bytes: [0x80, 0, 0, 0],
+        }
+        .fail()
+    }
+
+    #[test]
+    fn retry_identify_until_valid_id() {
+        let flash = Xflash::from_id(0xC2, 0x17);
+        let mut results = vec![
+            invalid_response(),
+            Ok(Xflash::from_id(0, 0)),
+            invalid_response(),
+            Ok(flash),
+        ]
+        .into_iter();
+        let mut retries = 0;
+
+        let xflash = retry_identify(
+            5,
+            || results.next().unwrap(),
+            || {
+                retries += 1;
+                Ok(())
+            },
+        );
+
+        assert_eq!(xflash.unwrap(), flash);
+        assert_eq!(retries, 3);
+    }
+
+    #[test]
+    fn retry_identify_returns_last_error() {
+        let mut tries = 0;
+        let mut retries = 0;
+
+        let xflash = retry_identify(
+            3,
+            || {
+                tries += 1;
+                invalid_response()
+            },
+            || {
+                retries += 1;
+                Ok(())
+            },
+        );
+
+        assert!(matches!(xflash, Err(Error::InvalidResponse { .. })));
+        assert_eq!((tries, retries), (3, 2));
+    }
+
+    #[test]
+    fn retry_identify_returns_zero_id_on_last_try() {
+        let mut tries = 0;
+
+        let xflash = retry_identify(
+            2,
+            || {
+                tries += 1;
+                Ok(Xflash::from_id(0, 0))
+            },
+            || Ok(()),
+        );
+
+        assert!(xflash.unwrap().is_zero_id());
+        assert_eq!(tries, 2);
+    }
+
+    #[test]
+    fn retry_identify_stops_on_other_errors() {
+        let mut retries = 0;
+
+        let xflash = retry_identify(
+            3,
+            || NoResponse {}.fail(),
+            || {
+                retries += 1;
+                Ok(())
+            },
+        );
+
+        assert!(matches!(xflash, Err(Error::NoResponse { .. })));
+        assert_eq!(retries, 0);
+    }
+
+    #[test]
+    fn retry_identify_tries_at_least_once() {
+        let mut tries = 0;
+
+        let xflash = retry_identify(
+            0,
+            || {
+                tries += 1;
+                Ok(Xflash::from_id(0xEF, 0x12))
+            },
+            || Ok(()),
+        );
+
+        assert!(xflash.is_ok());
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn erased_span_of_aligned_range() {
+        assert_eq!(erased_span(0, 0x1000), (0, 0x1000));
+        assert_eq!(erased_span(0x1000, 0x2000), (0x1000, 0x2000));
+    }
+
+    #[test]
+    fn erased_span_of_unaligned_range() {
+        assert_eq!(erased_span(0x800, 0x800), (0, 0x1000));
+        assert_eq!(erased_span(0x800, 0x1000), (0, 0x2000));
+        assert_eq!(erased_span(0x1FFF, 0x1001), (0x1000, 0x2000));
+    }
+
+    #[test]
+    fn erased_span_skips_sector_of_last_byte_on_sector_start() {
+        assert_eq!(erased_span(0x800, 0x801), (0, 0x1000));
+        assert_eq!(erased_span(0x1000, 1), (0x1000, 0));
+        assert_eq!(erased_span(0x1000, 0x1001), (0x1000, 0x1000));
+    }
+
+    #[test]
+    fn erased_span_of_empty_range() {
+        assert_eq!(erased_span(0, 0), (0, 0));
+        assert_eq!(erased_span(0x1000, 0), (0x1000, 0));
+        assert_eq!(erased_span(0x800, 0), (0, 0x1000));
+    }
+
+    #[test]
+    fn page_range_offsets() {
+        assert_eq!(page_range(0, 0), Some((0, 0)));
+        assert_eq!(page_range(0, 1), Some((0, 0x100)));
+        assert_eq!(page_range(2, 3), Some((0x200, 0x300)));
+        assert_eq!(page_range(0xFF_FFFE, 1), Some((0xFFFF_FE00, 0x100)));
+    }
+
+    #[test]
+    fn page_range_overflow() {
+        assert_eq!(page_range(0xFF_FFFF, 1), None);
+        assert_eq!(page_range(0x100_0000, 0), None);
+        assert_eq!(page_range(0, 0x100_0000), None);
+        assert_eq!(page_range(0x80_0000, 0x80_0000), None);
+    }
+
+    #[test]
+    fn snapshot_reads_by_flash_address() {
+        let snapshot = FlashSnapshot {
+            base: 0x1000,
+            data: vec![0x11, 0x22, 0x33],
+        };
+
+        assert_eq!(snapshot.read(0x0FFF), None);
+        assert_eq!(snapshot.read(0x1000), Some(0x11));
+        assert_eq!(snapshot.read(0x1002), Some(0x33));
+        assert_eq!(snapshot.read(0x1003), None);
+        assert_eq!(snapshot.read(0), None);
+    }
+
+    #[test]
+    fn snapshot_at_end_of_address_space() {
+        let snapshot = FlashSnapshot {
+            base: 0xFFFF_FFFE,
+            data: vec![0xAA, 0xBB],
+        };
+
+        assert_eq!(snapshot.read(0xFFFF_FFFD), None);
+        assert_eq!(snapshot.read(0xFFFF_FFFE), Some(0xAA));
+        assert_eq!(snapshot.read(u32::MAX), Some(0xBB));
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let snapshot = FlashSnapshot {
+            base: 0,
+            data: Vec::new(),
+        };
+
+        assert_eq!(snapshot.read(0), None);
+    }
+
+    #[test]
+    fn no_mismatched_sectors() {
+        let data = [0x5A; 0x20];
+
+        assert!(mismatched_sectors(0x1000, &data, &data).is_empty());
+        assert!(mismatched_sectors(0, &[], &[]).is_empty());
+    }
+
+    #[test]
+    fn mismatched_sectors_from_unaligned_offset() {
+        let data = vec![0x00; 0x1000];
+        let mut read_back = data.clone();
+        read_back[0] = 0xFF;
+        read_back[0x0FFF] = 0xFF;
+
+        assert_eq!(
+            mismatched_sectors(0x1800, &data, &read_back),
+            [0x1000, 0x2000]
+        );
+    }
+
+    #[test]
+    fn mismatched_sectors_in_partial_last_sector() {
+        let data = vec![0x00; 0x1010];
+        let mut read_back = data.clone();
+        read_back[0x100F] = 0x01;
+
+        assert_eq!(mismatched_sectors(0x3000, &data, &read_back), [0x4000]);
+    }
+
+    #[test]
+    fn mismatched_sectors_once_per_sector() {
+        let data = vec![0x00; 0x2000];
+        let read_back = vec![0xFF; 0x2000];
+
+        assert_eq!(mismatched_sectors(0, &data, &read_back), [0x0000, 0x1000]);
     }
 }
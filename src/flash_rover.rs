@@ -4,19 +4,21 @@
 // notice may not be copied, modified, or distributed except according to those terms.
 
 use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
 use std::time::Duration;
 
 use dss::com::ti::{
     ccstudio::scripting::environment::ScriptingEnvironment,
     debug::engine::scripting::{DebugServer, DebugSession},
 };
-use snafu::{Backtrace, ResultExt, Snafu};
+use snafu::{Backtrace, IntoError, ResultExt, Snafu};
 use tempfile::TempPath;
 
 use crate::assets;
 use crate::command::{Command, Subcommand};
-use crate::firmware::{self, Firmware};
-use crate::types::Device;
+use crate::firmware::{self, EraseMode, Firmware};
+use crate::hexdump;
+use crate::types::{Device, Endianness, SpiPins};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -30,11 +32,20 @@ pub enum Error {
         source: dss::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display("Lost connection to the device: {}", source))]
+    DssDisconnected {
+        source: Box<Error>,
+        backtrace: Backtrace,
+    },
     #[snafu(display("A Firmware error occured: {}", source))]
     FirmwareError {
         source: firmware::Error,
         backtrace: Backtrace,
     },
+    #[snafu(display(
+        "Unable to inject firmware as the device core is running, the core must be halted first"
+    ))]
+    TargetNotHalted { backtrace: Backtrace },
     #[snafu(display("Received too few bytes from input"))]
     InvalidInputLength { backtrace: Backtrace },
     #[snafu(display("Verification of written data failed"))]
@@ -114,8 +125,13 @@ impl<'a> FlashRover<'a> {
             .context(DssError {})?;
         debug_session.target.connect().context(DssError {})?;
 
-        let firmware = Firmware::new(debug_session.memory.clone(), command.device)
-            .context(FirmwareError {})?;
+        let firmware = Firmware::new(
+            debug_session.memory.clone(),
+            debug_session.target.clone(),
+            command.device,
+            command.firmware_config.clone(),
+        )
+        .context(FirmwareError {})?;
 
         Ok(Self {
             command,
@@ -126,12 +142,14 @@ impl<'a> FlashRover<'a> {
     }
 
     fn reset_into_firmware(&self) -> Result<()> {
+        self.reset_into_firmware_with(self.command.spi_pins)
+    }
+
+    fn reset_into_firmware_with(&self, spi_pins: Option<SpiPins>) -> Result<()> {
         const EXPRESSION_BOARD_RESET: &str =
             "GEL_AdvancedReset(\"Board Reset (automatic connect/disconnect)\")";
 
-        if !self.debug_session.target.is_halted().context(DssError {})? {
-            self.debug_session.target.halt().context(DssError {})?;
-        }
+        self.firmware.halt().context(FirmwareError {})?;
 
         self.debug_session.target.reset().context(DssError {})?;
         self.debug_session
@@ -139,52 +157,110 @@ impl<'a> FlashRover<'a> {
             .evaluate(EXPRESSION_BOARD_RESET)
             .context(DssError {})?;
 
-        self.firmware
-            .inject(self.command.spi_pins)
-            .context(FirmwareError {})?;
+        if let Err(err) = self.firmware.inject(spi_pins) {
+            // DSS only reports a generic error when writing to a running core
+            ensure!(
+                self.debug_session.target.is_halted().context(DssError {})?,
+                TargetNotHalted {}
+            );
+            return Err(err).context(FirmwareError {});
+        }
 
-        self.debug_session
-            .target
-            .run_asynch()
-            .context(DssError {})?;
+        self.firmware.resume().context(FirmwareError {})?;
 
         Ok(())
     }
 
+    /// Recovers firmware that stopped responding by resetting the device and
+    /// injecting the firmware again, then checks that the firmware responds.
+    /// Use after an error for which `is_firmware_hang` is true.
+    pub fn reset_and_reinject(&self) -> Result<()> {
+        self.reset_into_firmware()?;
+        self.firmware.get_xflash_info().context(FirmwareError {})?;
+
+        Ok(())
+    }
+
+    /// Runs `op`, and if the firmware hung, recovers with `reset_and_reinject`
+    /// and runs it once more. Only for operations which are safe to repeat
+    /// from the start.
+    fn with_recovery<T>(&self, op: impl Fn() -> firmware::Result<T>) -> Result<T> {
+        match op() {
+            Err(err) if is_firmware_hang(&err) => {
+                eprintln!("{}, resetting the device and retrying", err);
+                self.reset_and_reinject()?;
+                op().context(FirmwareError {})
+            }
+            result => result.context(FirmwareError {}),
+        }
+    }
+
     pub fn run(self) -> Result<()> {
+        self.run_subcommand().map_err(|err| {
+            if self.is_disconnected(&err) {
+                DssDisconnected {}.into_error(Box::new(err))
+            } else {
+                err
+            }
+        })
+    }
+
+    /// A DSS error is only considered a disconnect if the target itself reports
+    /// it is no longer connected, as DSS reports all failures the same way.
+    fn is_disconnected(&self, err: &Error) -> bool {
+        is_dss_error(err) && !self.debug_session.target.is_connected().unwrap_or(false)
+    }
+
+    fn run_subcommand(&self) -> Result<()> {
         use Subcommand::*;
 
         self.reset_into_firmware()?;
+        // Only count the traffic of the subcommand itself
+        self.firmware.reset_stats();
 
         match &self.command.subcommand {
             Info => self.info()?,
             SectorErase { offset, length } => self.sector_erase(*offset, *length)?,
-            MassErase => self.mass_erase()?,
+            MassErase { preserve } => self.mass_erase(preserve)?,
             Read {
                 offset,
                 length,
+                hexdump,
                 output,
-            } => self.read(*offset, *length, output.borrow_mut().as_mut())?,
+            } => self.read(*offset, *length, *hexdump, output.borrow_mut().as_mut())?,
             Write {
                 verify,
                 in_place,
+                mass_erase,
                 offset,
                 length,
                 input,
             } => self.write(
                 *verify,
                 *in_place,
+                *mass_erase,
                 *offset,
                 *length,
                 input.borrow_mut().as_mut(),
             )?,
+            Fill {
+                offset,
+                length,
+                word,
+                endianness,
+            } => self.fill(*offset, *length, *word, *endianness)?,
+            Discover { csn_range } => self.discover_pins(csn_range.clone())?,
+        }
+
+        if self.command.stats {
+            eprintln!("{}", self.firmware.stats());
         }
 
         Ok(())
     }
 
     fn info(&self) -> Result<()> {
-        let xflash_info = self.firmware.get_xflash_info().context(FirmwareError {})?;
+        let xflash_info = self.with_recovery(|| self.firmware.get_xflash_info())?;
 
         println!("{}", xflash_info);
 
@@ -192,37 +268,143 @@ impl<'a> FlashRover<'a> {
     }
 
     fn sector_erase(&self, offset: u32, length: u32) -> Result<()> {
-        self.firmware
-            .sector_erase(offset, length)
-            .context(FirmwareError {})?;
+        self.with_recovery(|| self.firmware.sector_erase(offset, length))?;
 
         Ok(())
     }
 
-    fn mass_erase(&self) -> Result<()> {
+    fn mass_erase(&self, preserve: &[(u32, u32)]) -> Result<()> {
         print!("Starting mass erase, this may take some time... ");
         io::stdout().flush().context(IoError {})?;
 
-        self.firmware.mass_erase().context(FirmwareError {})?;
+        if preserve.is_empty() {
+            self.with_recovery(|| self.firmware.mass_erase())?;
+        } else {
+            // Not retried, as the preserved data isn't on the flash anymore
+            // if the erase went ahead
+            self.firmware
+                .mass_erase_preserving(preserve)
+                .context(FirmwareError {})?;
+        }
 
         println!("Done.");
         Ok(())
     }
 
-    fn read(&self, offset: u32, length: u32, output: &mut dyn Write) -> Result<()> {
-        let data = self
-            .firmware
-            .read_data(offset, length)
+    fn discover_pins(&self, csn_range: RangeInclusive<u8>) -> Result<()> {
+        // Keep MISO, MOSI and CLK from --spi-pins if given, otherwise use the
+        // LaunchPad defaults
+        let candidate = match self.command.spi_pins {
+            Some(SpiPins([miso, mosi, clk, _])) => (miso, mosi, clk),
+            None => (8, 9, 10),
+        };
+
+        match self.discover(&[candidate], csn_range)? {
+            Some(spi_pins) => println!("Found external flash on SPI pins {}", spi_pins),
+            None => println!("No external flash found"),
+        }
+
+        Ok(())
+    }
+
+    /// Tries every CSN in `csn_range` with each of the `candidates` MISO, MOSI
+    /// and CLK pins, and returns the first pin set where the firmware reads a
+    /// flash ID that isn't all zeros or all ones. The flash doesn't have to be
+    /// a supported one. Each attempt resets the device and injects the
+    /// firmware again, so the scan takes a while for large ranges. Progress is
+    /// printed to stderr.
+    fn discover(
+        &self,
+        candidates: &[(u8, u8, u8)],
+        csn_range: RangeInclusive<u8>,
+    ) -> Result<Option<SpiPins>> {
+        for &(miso, mosi, clk) in candidates {
+            for csn in csn_range.clone() {
+                if [miso, mosi, clk].contains(&csn) {
+                    continue;
+                }
+
+                let spi_pins = SpiPins([miso, mosi, clk, csn]);
+                eprintln!("Trying SPI pins {}", spi_pins);
+
+                self.reset_into_firmware_with(Some(spi_pins))?;
+
+                match self.firmware.get_xflash_info() {
+                    // The firmware answers whenever the SPI transfer completes,
+                    // so a floating MISO still gives an ID of all ones or zeros
+                    Ok(xflash) if !xflash.is_zero_id() && !xflash.is_ones_id() => {
+                        return Ok(Some(spi_pins))
+                    }
+                    Ok(_) => {}
+                    // Only a missing flash means to keep going
+                    Err(err @ firmware::Error::DssError { .. }) => {
+                        return Err(err).context(FirmwareError {})
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn fill(&self, offset: u32, length: u32, word: u32, endianness: Endianness) -> Result<()> {
+        self.firmware
+            .fill_word(offset, length, word, endianness)
             .context(FirmwareError {})?;
-        io::copy(&mut data.as_slice(), output).context(IoError {})?;
 
         Ok(())
     }
 
+    fn read(&self, offset: u32, length: u32, hexdump: bool, output: &mut dyn Write) -> Result<()> {
+        let output_data = |offset: u32, data: &[u8], output: &mut dyn Write| -> Result<()> {
+            if hexdump {
+                hexdump::format(offset, data, output).context(IoError {})?;
+            } else {
+                output.write_all(data).context(IoError {})?;
+            }
+            Ok(())
+        };
+
+        // If the firmware hangs, recover once and resume from the failed block
+        let mut start = offset;
+        let mut recovered = false;
+
+        loop {
+            let err = match self.firmware.read_data(start, offset + length - start) {
+                Ok(data) => return output_data(start, &data, output),
+                Err(err) => err,
+            };
+
+            let failed = match &err {
+                firmware::Error::PartialRead {
+                    data,
+                    offset: failed,
+                    ..
+                } => {
+                    // Keep whatever was read before the failure
+                    output_data(start, data, output)?;
+                    *failed
+                }
+                _ => start,
+            };
+
+            if recovered || !is_firmware_hang(&err) {
+                return Err(err).context(FirmwareError {});
+            }
+
+            eprintln!("{}, resetting the device and resuming", err);
+            self.reset_and_reinject()?;
+            recovered = true;
+            start = failed;
+        }
+    }
+
     fn write(
         &self,
         verify: bool,
         in_place: bool,
+        mass_erase: bool,
         offset: u32,
         length: Option<u32>,
         input: &mut dyn Read,
@@ -238,66 +420,67 @@ impl<'a> FlashRover<'a> {
             vec
         };
 
-        let length = input_buf.len() as u32;
+        if in_place || mass_erase {
+            let erase = if in_place {
+                EraseMode::None
+            } else {
+                EraseMode::Mass
+            };
 
-        if in_place {
             self.firmware
-                .write_data(offset, &input_buf)
+                .program(offset, &input_buf, erase, false)
                 .context(FirmwareError {})?;
-
-            if verify {
-                self.reset_into_firmware()?;
-
-                let read_back = self
-                    .firmware
-                    .read_data(offset, length)
-                    .context(FirmwareError {})?;
-
-                ensure!(input_buf.eq(&read_back), VerificationFailed {});
-            }
         } else {
-            let first_address = offset - offset % firmware::BUF_SIZE;
-            let first_length = offset % firmware::BUF_SIZE;
-            let last_address = offset + length;
-            let last_length =
-                (firmware::BUF_SIZE - last_address % firmware::BUF_SIZE) % firmware::BUF_SIZE;
-
-            let first_sector_part: Vec<u8> = self
-                .firmware
-                .read_data(first_address, first_length)
+            // Retains the data outside the write range in the touched sectors
+            self.firmware
+                .write_sparse(&[(offset, input_buf.as_slice())])
                 .context(FirmwareError {})?;
-            let last_sector_part: Vec<u8> = self
+        }
+
+        if verify {
+            self.reset_and_reinject()?;
+
+            let read_back = self
                 .firmware
-                .read_data(last_address, last_length)
+                .read_data(offset, input_buf.len() as _)
                 .context(FirmwareError {})?;
 
-            let total_input: Vec<u8> = first_sector_part
-                .into_iter()
-                .chain(input_buf.into_iter())
-                .chain(last_sector_part.into_iter())
-                .collect();
-            let total_length = total_input.len() as u32;
+            ensure!(input_buf.eq(&read_back), VerificationFailed {});
+        }
 
-            self.firmware
-                .sector_erase(first_address, total_length)
-                .context(FirmwareError {})?;
-            self.firmware
-                .write_data(first_address, &total_input)
-                .context(FirmwareError {})?;
+        Ok(())
+    }
+}
 
-            if verify {
-                self.reset_into_firmware()?;
+/// Whether `err` means the firmware stopped responding, which
+/// `FlashRover::reset_and_reinject` can recover from
+pub fn is_firmware_hang(err: &firmware::Error) -> bool {
+    use firmware::Error::*;
 
-                let read_back = self
-                    .firmware
-                    .read_data(first_address, total_length)
-                    .context(FirmwareError {})?;
+    match err {
+        ResponseTimeout { .. } | FirmwareTimeout { .. } | CommandAckTimeout { .. } => true,
+        PartialRead { source, .. } | WriteStalled { source, .. } => is_firmware_hang(source),
+        _ => false,
+    }
+}
 
-                ensure!(total_input.eq(&read_back), VerificationFailed {});
-            }
-        }
+/// Whether `err` comes from DSS, also when it's the cause of a failed read
+/// or write
+fn is_dss_error(err: &Error) -> bool {
+    match err {
+        Error::DssError { .. } => true,
+        Error::FirmwareError { source, .. } => is_firmware_dss_error(source),
+        _ => false,
+    }
+}
 
-        Ok(())
+fn is_firmware_dss_error(err: &firmware::Error) -> bool {
+    use firmware::Error::*;
+
+    match err {
+        DssError { .. } => true,
+        PartialRead { source, .. } | WriteStalled { source, .. } => is_firmware_dss_error(source),
+        _ => false,
     }
 }
 
@@ -315,3 +498,72 @@ impl<'a> Drop for FlashRover<'a> {
         f().unwrap_or_default();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use snafu::GenerateBacktrace;
+
+    use super::*;
+
+    fn dss_error() -> firmware::Error {
+        firmware::Error::DssError {
+            source: dss::Error::from("target disconnected"),
+            backtrace: Backtrace::generate(),
+        }
+    }
+
+    fn timeout() -> firmware::Error {
+        firmware::Error::ResponseTimeout {
+            command: firmware::Command::ReadBlock {
+                offset: 0,
+                length: 0x1000,
+            },
+            command_acked: true,
+            backtrace: Backtrace::generate(),
+        }
+    }
+
+    fn partial_read(source: firmware::Error) -> firmware::Error {
+        firmware::Error::PartialRead {
+            data: vec![0xFF; 0x1000],
+            offset: 0x1000,
+            elapsed: Duration::from_millis(300),
+            source: Box::new(source),
+            backtrace: Backtrace::generate(),
+        }
+    }
+
+    fn write_stalled(source: firmware::Error) -> firmware::Error {
+        firmware::Error::WriteStalled {
+            offset: 0x2000,
+            bytes_done: 0x2000,
+            elapsed: Duration::from_millis(500),
+            source: Box::new(source),
+            backtrace: Backtrace::generate(),
+        }
+    }
+
+    fn wrapped(source: firmware::Error) -> Error {
+        FirmwareError {}.into_error(source)
+    }
+
+    #[test]
+    fn dss_error_shapes() {
+        let direct: Result<()> = Err(dss::Error::from("target disconnected")).context(DssError {});
+
+        assert!(is_dss_error(&direct.unwrap_err()));
+        assert!(is_dss_error(&wrapped(dss_error())));
+        assert!(is_dss_error(&wrapped(partial_read(dss_error()))));
+        assert!(is_dss_error(&wrapped(write_stalled(dss_error()))));
+    }
+
+    #[test]
+    fn other_error_shapes() {
+        assert!(!is_dss_error(&wrapped(timeout())));
+        assert!(!is_dss_error(&wrapped(partial_read(timeout()))));
+        assert!(!is_dss_error(&wrapped(write_stalled(timeout()))));
+
+        let other: Result<()> = InvalidInputLength {}.fail();
+        assert!(!is_dss_error(&other.unwrap_err()));
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2020 , Texas Instruments.
+// Licensed under the BSD-3-Clause license
+// (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
+// notice may not be copied, modified, or distributed except according to those terms.
+
+use std::io::{self, Write};
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Writes `data` in the same format as `xxd`, with the address column starting
+/// at the external flash `offset` the data was read from.
+pub fn format(offset: u32, data: &[u8], out: &mut dyn Write) -> io::Result<()> {
+    for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let address = u64::from(offset) + (i * BYTES_PER_LINE) as u64;
+        write!(out, "{:08x}: ", address)?;
+
+        for j in 0..BYTES_PER_LINE {
+            match line.get(j) {
+                Some(byte) => write!(out, "{:02x}", byte)?,
+                // Pad a partial last line so the ASCII column stays aligned
+                None => write!(out, "  ")?,
+            }
+            if j % 2 == 1 {
+                write!(out, " ")?;
+            }
+        }
+
+        let ascii: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, " {}", ascii)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(offset: u32, data: &[u8]) -> String {
+        let mut out = Vec::new();
+        format(offset, data, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn full_line() {
+        assert_eq!(
+            dump(0x1000, b"0123456789abcdef"),
+            "00001000: 3031 3233 3435 3637 3839 6162 6364 6566  0123456789abcdef\n"
+        );
+    }
+
+    #[test]
+    fn pads_partial_last_line() {
+        assert_eq!(
+            dump(0x20, &[0x41, 0x00, 0x7F]),
+            "00000020: 4100 7f                                  A..\n"
+        );
+    }
+
+    #[test]
+    fn addresses_follow_unaligned_offset() {
+        let data: Vec<u8> = (0x30..0x44).collect();
+
+        assert_eq!(
+            dump(0x03, &data),
+            "00000003: 3031 3233 3435 3637 3839 3a3b 3c3d 3e3f  0123456789:;<=>?\n\
+             00000013: 4041 4243                                @ABC\n"
+        );
+    }
+
+    #[test]
+    fn non_printable_bytes_as_dots() {
+        assert_eq!(
+            dump(0, &[0x0A, b' ', b'~', 0xFF]),
+            "00000000: 0a20 7eff                                . ~.\n"
+        );
+    }
+
+    #[test]
+    fn empty_data() {
+        assert_eq!(dump(0, &[]), "");
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2020 , Texas Instruments.
+// Licensed under the BSD-3-Clause license
+// (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
+// notice may not be copied, modified, or distributed except according to those terms.
+
+use snafu::{Backtrace, Snafu};
+
+use crate::firmware;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Segment at 0x{:X} overlaps the segment at 0x{:X} of {} bytes",
+        offset,
+        previous_offset,
+        previous_length
+    ))]
+    OverlappingSegments {
+        offset: u32,
+        previous_offset: u32,
+        previous_length: usize,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "Segment at 0x{:X} of {} bytes exceeds the 32-bit address space",
+        offset,
+        length
+    ))]
+    SegmentOverflow {
+        offset: u32,
+        length: usize,
+        backtrace: Backtrace,
+    },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The value of erased flash, and so the natural fill for gaps
+pub const DEFAULT_FILL: u8 = 0xFF;
+
+/// Assembles segments at flash offsets into one contiguous image
+#[derive(Clone, Debug, Default)]
+pub struct ImageBuilder {
+    segments: Vec<(u32, Vec<u8>)>,
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `bytes` to be placed at flash offset `offset`. Segments can be
+    /// added in any order.
+    pub fn add(mut self, offset: u32, bytes: impl Into<Vec<u8>>) -> Self {
+        self.segments.push((offset, bytes.into()));
+        self
+    }
+
+    /// Lays out the segments from the lowest offset, padding the gaps with
+    /// `fill`, and returns that offset along with the image. The image can
+    /// be written with `Firmware::write_data` at the returned offset, so
+    /// segments must end within the same address range it accepts. Empty
+    /// segments are ignored, and overlapping ones are an error.
+    pub fn build(&self, fill: u8) -> Result<(u32, Vec<u8>)> {
+        let mut segments: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .collect();
+        segments.sort_by_key(|(offset, _)| *offset);
+
+        for (offset, bytes) in &segments {
+            ensure!(
+                firmware::in_address_range(*offset, bytes.len()),
+                SegmentOverflow {
+                    offset: *offset,
+                    length: bytes.len()
+                }
+            );
+        }
+
+        for pair in segments.windows(2) {
+            let (previous_offset, previous) = pair[0];
+            let (offset, _) = pair[1];
+            ensure!(
+                u64::from(*previous_offset) + previous.len() as u64 <= u64::from(*offset),
+                OverlappingSegments {
+                    offset: *offset,
+                    previous_offset: *previous_offset,
+                    previous_length: previous.len()
+                }
+            );
+        }
+
+        let base = match segments.first() {
+            Some((offset, _)) => *offset,
+            None => return Ok((0, Vec::new())),
+        };
+
+        let mut image = Vec::new();
+        for (offset, bytes) in segments {
+            image.resize((offset - base) as usize, fill);
+            image.extend_from_slice(bytes);
+        }
+
+        Ok((base, image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_builder() {
+        assert_eq!(
+            ImageBuilder::new().build(DEFAULT_FILL).unwrap(),
+            (0, vec![])
+        );
+    }
+
+    #[test]
+    fn pads_gaps_with_fill() {
+        let (base, image) = ImageBuilder::new()
+            .add(0x104, vec![3u8, 4])
+            .add(0x100, vec![1u8, 2])
+            .build(DEFAULT_FILL)
+            .unwrap();
+
+        assert_eq!(base, 0x100);
+        assert_eq!(image, [1, 2, 0xFF, 0xFF, 3, 4]);
+    }
+
+    #[test]
+    fn adjacent_segments() {
+        let (base, image) = ImageBuilder::new()
+            .add(2, vec![3u8])
+            .add(0, vec![1u8, 2])
+            .build(0)
+            .unwrap();
+
+        assert_eq!(base, 0);
+        assert_eq!(image, [1, 2, 3]);
+    }
+
+    #[test]
+    fn ignores_empty_segments() {
+        let (base, image) = ImageBuilder::new()
+            .add(0, Vec::<u8>::new())
+            .add(0x10, vec![1u8])
+            .build(DEFAULT_FILL)
+            .unwrap();
+
+        assert_eq!(base, 0x10);
+        assert_eq!(image, [1]);
+    }
+
+    #[test]
+    fn rejects_overlap() {
+        let result = ImageBuilder::new()
+            .add(0, vec![0u8; 4])
+            .add(3, vec![0u8; 4])
+            .build(DEFAULT_FILL);
+
+        assert!(matches!(
+            result,
+            Err(Error::OverlappingSegments {
+                offset: 3,
+                previous_offset: 0,
+                previous_length: 4,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_segment_past_address_space() {
+        let result = ImageBuilder::new()
+            .add(u32::MAX, vec![0u8; 2])
+            .build(DEFAULT_FILL);
+
+        assert!(matches!(
+            result,
+            Err(Error::SegmentOverflow {
+                offset: u32::MAX,
+                length: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_segment_ending_at_address_space_end() {
+        let result = ImageBuilder::new()
+            .add(u32::MAX, vec![7u8])
+            .build(DEFAULT_FILL);
+
+        assert!(matches!(
+            result,
+            Err(Error::SegmentOverflow {
+                offset: u32::MAX,
+                length: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn allows_segment_within_writable_range() {
+        let (base, image) = ImageBuilder::new()
+            .add(u32::MAX - 1, vec![7u8])
+            .build(DEFAULT_FILL)
+            .unwrap();
+
+        assert_eq!(base, u32::MAX - 1);
+        assert_eq!(image, [7]);
+    }
+}
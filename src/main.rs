@@ -31,9 +31,14 @@ mod app;
 mod args;
 mod assets;
 mod command;
+mod diff;
 mod dss_logger;
 mod firmware;
 mod flash_rover;
+mod hexdump;
+mod image;
+mod metadata;
+mod stats;
 mod types;
 mod xflash;
 
@@ -0,0 +1,155 @@
+// Copyright (c) 2020 , Texas Instruments.
+// Licensed under the BSD-3-Clause license
+// (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
+// notice may not be copied, modified, or distributed except according to those terms.
+
+use std::convert::TryInto;
+
+/// Image IDs which start a valid header, for the CC26x2/CC13x2 and CC26x0
+/// OAD stacks
+const IMAGE_IDS: &[&[u8; 8]] = &[b"CC26x2R1", b"CC13x2R1", b"OAD IMG "];
+
+/// The fixed part of the OAD image header placed in front of images stored
+/// on the external flash. All fields are little-endian and packed:
+///
+/// | Offset | Size | Field          |
+/// |--------|------|----------------|
+/// | 0      | 8    | image ID       |
+/// | 8      | 4    | CRC32          |
+/// | 12     | 1    | BIM version    |
+/// | 13     | 1    | header version |
+/// | 14     | 2    | technology     |
+/// | 16     | 1    | copy status    |
+/// | 17     | 1    | CRC status     |
+/// | 18     | 1    | image type     |
+/// | 19     | 1    | image number   |
+/// | 20     | 4    | image validity |
+/// | 24     | 4    | length         |
+/// | 28     | 4    | program entry  |
+/// | 32     | 4    | SW version     |
+/// | 36     | 4    | end address    |
+/// | 40     | 2    | header length  |
+/// | 42     | 2    | reserved       |
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashHeader {
+    pub image_id: [u8; 8],
+    pub crc32: u32,
+    pub bim_version: u8,
+    pub header_version: u8,
+    pub tech_type: u16,
+    pub image_type: u8,
+    pub image_number: u8,
+    pub length: u32,
+    pub program_entry: u32,
+    pub software_version: [u8; 4],
+    pub end_address: u32,
+    pub header_length: u16,
+}
+
+impl FlashHeader {
+    /// Number of bytes the header takes up on the flash
+    pub const SIZE: u32 = 44;
+
+    /// Parses a header from the start of `bytes`, or `None` if they are too
+    /// short or don't start with a known image ID
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::SIZE as usize {
+            return None;
+        }
+
+        let image_id: [u8; 8] = bytes[0..8].try_into().ok()?;
+        if !IMAGE_IDS.contains(&&image_id) {
+            return None;
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        Some(Self {
+            image_id,
+            crc32: u32_at(8),
+            bim_version: bytes[12],
+            header_version: bytes[13],
+            tech_type: u16_at(14),
+            image_type: bytes[18],
+            image_number: bytes[19],
+            length: u32_at(24),
+            program_entry: u32_at(28),
+            software_version: bytes[32..36].try_into().ok()?,
+            end_address: u32_at(36),
+            header_length: u16_at(40),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(image_id: &[u8; 8]) -> Vec<u8> {
+        let mut bytes = image_id.to_vec();
+        bytes.extend_from_slice(&0x1122_3344u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x03, 0x01]);
+        bytes.extend_from_slice(&0xFFFEu16.to_le_bytes());
+        bytes.extend_from_slice(&[0xFE, 0xFC, 0x01, 0x02]);
+        bytes.extend_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        bytes.extend_from_slice(&0x0001_2000u32.to_le_bytes());
+        bytes.extend_from_slice(&0x0000_0051u32.to_le_bytes());
+        bytes.extend_from_slice(b"0001");
+        bytes.extend_from_slice(&0x0001_1FFFu32.to_le_bytes());
+        bytes.extend_from_slice(&0x0050u16.to_le_bytes());
+        bytes.extend_from_slice(&[0xFF, 0xFF]);
+        bytes
+    }
+
+    #[test]
+    fn parses_fields_at_their_offsets() {
+        let bytes = header_bytes(b"CC26x2R1");
+        assert_eq!(bytes.len(), FlashHeader::SIZE as usize);
+
+        assert_eq!(
+            FlashHeader::parse(&bytes),
+            Some(FlashHeader {
+                image_id: *b"CC26x2R1",
+                crc32: 0x1122_3344,
+                bim_version: 0x03,
+                header_version: 0x01,
+                tech_type: 0xFFFE,
+                image_type: 0x01,
+                image_number: 0x02,
+                length: 0x0001_2000,
+                program_entry: 0x0000_0051,
+                software_version: *b"0001",
+                end_address: 0x0001_1FFF,
+                header_length: 0x0050,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_header_followed_by_image() {
+        let mut bytes = header_bytes(b"OAD IMG ");
+        bytes.extend_from_slice(&[0x00; 16]);
+
+        let header = FlashHeader::parse(&bytes).unwrap();
+        assert_eq!(&header.image_id, b"OAD IMG ");
+        assert_eq!(header.header_length, 0x0050);
+    }
+
+    #[test]
+    fn rejects_short_input() {
+        let bytes = header_bytes(b"CC13x2R1");
+
+        assert!(FlashHeader::parse(&bytes[..bytes.len() - 1]).is_none());
+        assert!(FlashHeader::parse(&bytes[..8]).is_none());
+        assert!(FlashHeader::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_image_id() {
+        assert!(FlashHeader::parse(&header_bytes(b"CC26x0R2")).is_none());
+        assert!(FlashHeader::parse(&[0xFF; FlashHeader::SIZE as usize]).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2020 , Texas Instruments.
+// Licensed under the BSD-3-Clause license
+// (see LICENSE or <https://opensource.org/licenses/BSD-3-Clause>) All files in the project
+// notice may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub commands: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    pub errors: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Read {} bytes, wrote {} bytes, sent {} commands ({} retries, {} timeouts, {} errors)",
+            self.bytes_read,
+            self.bytes_written,
+            self.commands,
+            self.retries,
+            self.timeouts,
+            self.errors,
+        )
+    }
+}
+
+/// Upper bounds of the poll count histogram buckets, with one more bucket for
+/// everything above the last bound
+pub const POLL_BUCKETS: [u32; 6] = [1, 2, 4, 8, 16, 32];
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PollStats {
+    /// Number of commands in each bucket of `POLL_BUCKETS`
+    pub histogram: [u64; POLL_BUCKETS.len() + 1],
+}
+
+impl fmt::Display for PollStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (bound, count) in POLL_BUCKETS.iter().zip(&self.histogram) {
+            write!(f, "<={}: {}, ", bound, count)?;
+        }
+        write!(
+            f,
+            ">{}: {}",
+            POLL_BUCKETS[POLL_BUCKETS.len() - 1],
+            self.histogram[POLL_BUCKETS.len()]
+        )
+    }
+}
+
+/// Counters are only used for telemetry and never to synchronize, so relaxed
+/// ordering is sufficient
+#[derive(Debug, Default)]
+pub struct StatsCounters {
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub commands: AtomicU64,
+    pub retries: AtomicU64,
+    pub timeouts: AtomicU64,
+    pub errors: AtomicU64,
+    pub polls: [AtomicU64; POLL_BUCKETS.len() + 1],
+}
+
+impl StatsCounters {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn add_polls(&self, polls: u32) {
+        let bucket = POLL_BUCKETS
+            .iter()
+            .position(|&bound| polls <= bound)
+            .unwrap_or(POLL_BUCKETS.len());
+        Self::add(&self.polls[bucket], 1);
+    }
+
+    pub fn poll_snapshot(&self) -> PollStats {
+        let mut stats = PollStats::default();
+        for (count, counter) in stats.histogram.iter_mut().zip(&self.polls) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in &[
+            &self.bytes_read,
+            &self.bytes_written,
+            &self.commands,
+            &self.retries,
+            &self.timeouts,
+            &self.errors,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for counter in &self.polls {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -4,16 +4,27 @@
 // notice may not be copied, modified, or distributed except according to those terms.
 
 use std::convert::TryFrom;
+use std::fmt;
 use std::ops;
 use std::str;
 use std::string;
 
 use snafu::{Backtrace, OptionExt, Snafu};
 
+use crate::assets;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Invalid string when parsing Device: {}", input))]
-    InvalidDevice { input: String, backtrace: Backtrace },
+    #[snafu(display(
+        "Invalid string when parsing Device: {}, expected one of: {}",
+        input,
+        supported
+    ))]
+    InvalidDevice {
+        input: String,
+        supported: String,
+        backtrace: Backtrace,
+    },
     #[snafu(display("Unable to parse SPI pins: {}", msg))]
     InvalidSpiPins { msg: String, backtrace: Backtrace },
 }
@@ -38,6 +49,17 @@ pub enum Device {
 }
 
 impl Device {
+    /// Every device variant, whether or not this build has assets for it. See
+    /// `assets::supported_devices` for the devices which can actually be used.
+    pub fn all() -> &'static [Device] {
+        use Device::*;
+
+        &[
+            CC1310, CC1312R, CC1350, CC1352P, CC1352R, CC2640, CC2640R2F, CC2642R, CC2650, CC2652P,
+            CC2652R, CC2652RB,
+        ]
+    }
+
     pub fn ccxml_desc(&self) -> &str {
         use Device::*;
 
@@ -123,7 +145,15 @@ impl str::FromStr for Device {
             "cc2652p" => Ok(CC2652P),
             "cc2652r" => Ok(CC2652R),
             "cc2652rb" => Ok(CC2652RB),
-            input => InvalidDevice { input }.fail(),
+            input => InvalidDevice {
+                input,
+                supported: assets::supported_devices()
+                    .iter()
+                    .map(Device::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }
+            .fail(),
         }
     }
 }
@@ -151,6 +181,12 @@ impl From<Device> for DeviceFamily {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum SpiPin {
     Miso,
@@ -197,6 +233,74 @@ impl TryFrom<Vec<String>> for SpiPins {
 
         let dios: [_; 4] = [dios[0], dios[1], dios[2], dios[3]];
 
+        for (i, dio) in dios.iter().enumerate() {
+            ensure!(
+                !dios[..i].contains(dio),
+                InvalidSpiPins {
+                    msg: format!("SPI pins must be unique, DIO {} is used twice", dio)
+                }
+            );
+        }
+
         Ok(Self(dios))
     }
 }
+
+/// Parses the same "MISO,MOSI,CLK,CSN" format as the --spi-pins argument
+impl str::FromStr for SpiPins {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dios: Vec<String> = s.split(',').map(|dio| dio.trim().to_string()).collect();
+
+        Self::try_from(dios)
+    }
+}
+
+impl fmt::Display for SpiPins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [miso, mosi, clk, csn] = self.0;
+        write!(f, "{},{},{},{}", miso, mosi, clk, csn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spi_pins_round_trip() {
+        let spi_pins: SpiPins = "8,9,10,20".parse().unwrap();
+        assert_eq!(spi_pins.0, [8, 9, 10, 20]);
+        assert_eq!(spi_pins.to_string(), "8,9,10,20");
+
+        let reparsed: SpiPins = spi_pins.to_string().parse().unwrap();
+        assert_eq!(reparsed.0, spi_pins.0);
+    }
+
+    #[test]
+    fn spi_pins_trims_whitespace() {
+        let spi_pins: SpiPins = " 1, 2 ,3,4 ".parse().unwrap();
+        assert_eq!(spi_pins.0, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn spi_pins_rejects_duplicates() {
+        assert!(matches!(
+            "8,9,9,20".parse::<SpiPins>(),
+            Err(Error::InvalidSpiPins { .. })
+        ));
+    }
+
+    #[test]
+    fn spi_pins_rejects_wrong_count() {
+        assert!("8,9,10".parse::<SpiPins>().is_err());
+        assert!("8,9,10,20,21".parse::<SpiPins>().is_err());
+    }
+
+    #[test]
+    fn spi_pins_rejects_invalid_values() {
+        assert!("8,9,10,256".parse::<SpiPins>().is_err());
+        assert!("8,9,10,csn".parse::<SpiPins>().is_err());
+    }
+}
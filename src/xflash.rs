@@ -7,19 +7,22 @@ use std::fmt;
 
 use byte_unit::Byte;
 
+/// Erase sector size of all the known flashes
+pub const SECTOR_SIZE: u32 = 0x1000;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct XflashId {
     mid: u32,
     did: u32,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct XflashInfo {
     name: &'static str,
     size: u32,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Xflash {
     Known(XflashId, XflashInfo),
     Unknown(XflashId),
@@ -184,4 +187,86 @@ impl Xflash {
             .copied()
             .unwrap_or(Xflash::Unknown(id))
     }
+
+    /// Size in bytes of a known flash. The table lists sizes in bits.
+    pub fn capacity(&self) -> Option<u32> {
+        match self {
+            Xflash::Known(_, info) => Some(info.size / 8),
+            Xflash::Unknown(_) => None,
+        }
+    }
+
+    /// The `(start, end)` byte range of a known flash that's free to write.
+    /// None of the supported flashes reserve blocks in the main array, their
+    /// security and OTP areas are separate address spaces, so this is always
+    /// the whole capacity for now.
+    pub fn usable_range(&self) -> Option<(u32, u32)> {
+        self.capacity().map(|capacity| (0, capacity))
+    }
+
+    /// The `(offset, size)` of each erase sector of a known flash, in
+    /// address order, or empty for an unknown one. All supported flashes have
+    /// uniform 4 KiB sectors, the same `SECTOR_SIZE` that `sector_erase`
+    /// aligns to, so there are no boot sectors to account for yet.
+    pub fn sector_map(&self) -> Vec<(u32, u32)> {
+        match self.capacity() {
+            Some(capacity) => (0..capacity)
+                .step_by(SECTOR_SIZE as usize)
+                .map(|offset| (offset, SECTOR_SIZE))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether both IDs read as zero, which is what a MISO stuck low reads as
+    pub fn is_zero_id(&self) -> bool {
+        let id = match self {
+            Xflash::Known(id, _) => id,
+            Xflash::Unknown(id) => id,
+        };
+        id.mid == 0 && id.did == 0
+    }
+
+    /// Whether both IDs read as all ones, which is what a floating MISO with
+    /// no flash on the bus typically reads as
+    pub fn is_ones_id(&self) -> bool {
+        let id = match self {
+            Xflash::Known(id, _) => id,
+            Xflash::Unknown(id) => id,
+        };
+        id.mid == 0xFF && id.did == 0xFF
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sector_map_of_known_flash() {
+        // Macronix MX25R512F, 64 KiB
+        let sectors = Xflash::from_id(0xC2, 0x10).sector_map();
+
+        assert_eq!(sectors.len(), 16);
+        assert_eq!(sectors.first(), Some(&(0x0000, SECTOR_SIZE)));
+        assert_eq!(sectors.last(), Some(&(0xF000, SECTOR_SIZE)));
+    }
+
+    #[test]
+    fn sector_map_covers_capacity() {
+        for xflash in SUPPORTED_HW {
+            let sectors = xflash.sector_map();
+            let mut next = 0;
+            for &(offset, size) in &sectors {
+                assert_eq!(offset, next, "gap in sector map of {}", xflash);
+                next = offset + size;
+            }
+            assert_eq!(Some(next), xflash.capacity(), "{}", xflash);
+        }
+    }
+
+    #[test]
+    fn sector_map_of_unknown_flash() {
+        assert!(Xflash::from_id(0x12, 0x34).sector_map().is_empty());
+    }
 }